@@ -0,0 +1,9 @@
+CREATE TABLE zaps (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT,
+    owner TEXT,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    nodes TEXT NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE zap_versions;
@@ -0,0 +1,8 @@
+CREATE TABLE zap_versions (
+    zap_id TEXT NOT NULL REFERENCES zaps (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    author TEXT,
+    created_at TIMESTAMP NOT NULL,
+    nodes TEXT NOT NULL,
+    PRIMARY KEY (zap_id, version)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE runs (
+    id TEXT PRIMARY KEY NOT NULL,
+    zap_id TEXT,
+    trigger_source TEXT NOT NULL,
+    status TEXT NOT NULL,
+    started_at TIMESTAMP NOT NULL,
+    finished_at TIMESTAMP,
+    report TEXT NOT NULL
+);
+
+CREATE INDEX runs_zap_id_idx ON runs (zap_id);
@@ -0,0 +1 @@
+ALTER TABLE zaps DROP COLUMN version;
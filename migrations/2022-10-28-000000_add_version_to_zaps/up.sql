@@ -0,0 +1 @@
+ALTER TABLE zaps ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
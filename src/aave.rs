@@ -0,0 +1,248 @@
+use std::env;
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde_json::Value;
+use web3::contract::tokens::Tokenize;
+use web3::ethabi::{self, Token};
+use web3::transports::Http;
+use web3::types::{Address, U256};
+use web3::Web3;
+
+use crate::dag::ActionType;
+use crate::erc20::Approval;
+use crate::execution::{ActionOutcome, Submission};
+use crate::multicall::Read;
+use crate::{chain, erc20, router};
+
+/// Which rate a borrow accrues interest at, and so which debt a repayment pays off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateMode {
+    Stable,
+    Variable,
+}
+
+impl RateMode {
+    /// The `interestRateMode` the pool takes.
+    fn as_uint(self) -> U256 {
+        match self {
+            RateMode::Stable => U256::one(),
+            RateMode::Variable => U256::from(2),
+        }
+    }
+}
+
+impl FromStr for RateMode {
+    type Err = anyhow::Error;
+
+    fn from_str(rate_mode: &str) -> anyhow::Result<Self> {
+        match rate_mode {
+            "stable" => Ok(RateMode::Stable),
+            "variable" => Ok(RateMode::Variable),
+            _ => Err(anyhow::anyhow!("{} is neither stable nor variable", rate_mode)),
+        }
+    }
+}
+
+/// What `getUserAccountData` reports. Amounts are in the pool's base currency and ratios in
+/// basis points.
+#[derive(Debug)]
+pub struct AccountData {
+    pub total_collateral_base: U256,
+    pub total_debt_base: U256,
+    pub available_borrows_base: U256,
+    pub current_liquidation_threshold: U256,
+    pub ltv: U256,
+    /// Scaled by `10^18`. Below 1 the account can be liquidated, and without debt it is
+    /// `2^256 - 1`.
+    pub health_factor: U256,
+}
+
+impl AccountData {
+    /// The health factor as a plain number, which is what edge conditions compare.
+    pub fn health_factor(&self) -> anyhow::Result<f64> {
+        Ok(self.health_factor.to_string().parse::<f64>()? / 1e18)
+    }
+}
+
+/// The lending pool from `AAVE_POOL_ADDRESS`, for nodes that don't set their own `pool_address`.
+pub fn pool_address() -> anyhow::Result<Address> {
+    let pool_address = env::var("AAVE_POOL_ADDRESS").context("AAVE_POOL_ADDRESS must be set")?;
+    Address::from_str(&pool_address).context("Invalid Aave pool address")
+}
+
+fn abi() -> anyhow::Result<ethabi::Contract> {
+    ethabi::Contract::load(&include_bytes!("./aave_pool_abi.json")[..])
+        .context("Invalid Aave pool ABI")
+}
+
+/// The `getUserAccountData` call `account_data` decodes.
+pub fn account_data_read(pool: Address, user: Address) -> anyhow::Result<Read> {
+    let data = abi()?.function("getUserAccountData")?.encode_input(&[Token::Address(user)])?;
+    Ok(Read::Call { target: pool, data })
+}
+
+pub fn account_data(data: &[u8]) -> anyhow::Result<AccountData> {
+    let mut values = abi()?
+        .function("getUserAccountData")?
+        .decode_output(data)
+        .context("Invalid getUserAccountData output")?
+        .into_iter();
+    let mut next = || values.next().and_then(Token::into_uint).context("Missing account data");
+
+    Ok(AccountData {
+        total_collateral_base: next()?,
+        total_debt_base: next()?,
+        available_borrows_base: next()?,
+        current_liquidation_threshold: next()?,
+        ltv: next()?,
+        health_factor: next()?,
+    })
+}
+
+/// The token tracking the signing account's `rate_mode` debt in `asset`, whose balance is what
+/// it owes.
+async fn debt_token(
+    web3: &Web3<Http>, pool: Address, asset: Address, rate_mode: RateMode,
+) -> anyhow::Result<Address> {
+    let data = abi()?.function("getReserveData")?.encode_input(&[Token::Address(asset)])?;
+    let output = chain::call(web3, pool, data).await?;
+    reserve_debt_token(&output.0, rate_mode)
+}
+
+/// Reads the debt token out of what `getReserveData` returned. `ReserveData` only holds static
+/// fields, one word each, and V3 inserted `id` before the token addresses and three fields after
+/// them, so the 12 field V2 layout and the 15 field V3 one are told apart by their size.
+fn reserve_debt_token(output: &[u8], rate_mode: RateMode) -> anyhow::Result<Address> {
+    let (stable, variable) = match output.len() / 32 {
+        _ if output.len() % 32 != 0 => anyhow::bail!("Invalid getReserveData output"),
+        12 => (8, 9),
+        15 => (9, 10),
+        fields => anyhow::bail!("Unknown ReserveData layout with {} fields", fields),
+    };
+    let index = match rate_mode {
+        RateMode::Stable => stable,
+        RateMode::Variable => variable,
+    };
+
+    Ok(Address::from_slice(&output[index * 32 + 12..(index + 1) * 32]))
+}
+
+/// What repaying the whole `rate_mode` debt in `asset` will take. The debt keeps accruing
+/// interest until the repayment is mined, so a basis point is added on top.
+async fn full_repayment(
+    web3: &Web3<Http>, pool: Address, asset: Address, rate_mode: RateMode,
+) -> anyhow::Result<U256> {
+    let debt_token = debt_token(web3, pool, asset, rate_mode).await?;
+    let debt = erc20::balance_of(web3, debt_token, chain::account()?).await?;
+    Ok(debt.saturating_add(debt / 10_000))
+}
+
+/// Supplies, withdraws, borrows or repays `amount` of `asset` through `pool` for the signing
+/// account. Supplies and repayments approve the pool first if `AUTO_APPROVE` allows it. An
+/// `amount` of `2^256 - 1` withdraws everything supplied or repays the whole debt, in which case
+/// the pool is approved for the current debt. Publishes `asset` and, unless it was everything,
+/// `amount`.
+pub async fn execute(
+    action_type: ActionType, pool: Address, asset: Address, amount: U256, rate_mode: RateMode,
+    dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let account = chain::account()?;
+    // Nobody referred the account, which the pool takes as code 0.
+    let referral_code = 0_u16;
+    let interest_rate_mode = rate_mode.as_uint();
+
+    let (function_name, params, spends) = match action_type {
+        ActionType::AaveSupply => {
+            ("supply", (asset, amount, account, referral_code).into_tokens(), true)
+        },
+        ActionType::AaveDeposit => {
+            ("deposit", (asset, amount, account, referral_code).into_tokens(), true)
+        },
+        ActionType::AaveWithdraw => ("withdraw", (asset, amount, account).into_tokens(), false),
+        ActionType::AaveBorrow => {
+            let params = (asset, amount, interest_rate_mode, referral_code, account);
+            ("borrow", params.into_tokens(), false)
+        },
+        ActionType::AaveRepay => {
+            ("repay", (asset, amount, interest_rate_mode, account).into_tokens(), true)
+        },
+        _ => return Err(anyhow::anyhow!("{:?} is not an Aave action", action_type)),
+    };
+
+    let spends = match (spends, action_type) {
+        (false, _) => vec![],
+        (true, ActionType::AaveRepay) if amount == U256::MAX => {
+            vec![(asset, full_repayment(&web3s, pool, asset, rate_mode).await?)]
+        },
+        (true, _) => vec![(asset, amount)],
+    };
+    let mut outcome = ActionOutcome::default();
+    let result: anyhow::Result<()> = async {
+        let approval = erc20::approve_spends(&web3s, pool, &spends, dry_run, &mut outcome).await?;
+        if approval == Approval::Stopped {
+            return Ok(());
+        }
+
+        let data = abi()?
+            .function(function_name)?
+            .encode_input(&params)
+            .with_context(|| format!("Failed to encode {}", function_name))?;
+        let pending = approval == Approval::Pending;
+        let parameters = router::transaction(&web3s, pool, U256::zero(), data, pending).await?;
+        let submission = if pending {
+            // The call can't be simulated before the approval it relies on is on chain.
+            Submission::unsent(parameters)
+        } else {
+            chain::submit(&web3s, parameters, dry_run).await?
+        };
+
+        outcome.outputs.extend(chain::submission_outputs(&submission));
+        outcome.outputs.insert("asset".to_string(), Value::String(format!("{:?}", asset)));
+        if amount != U256::MAX {
+            outcome.outputs.insert("amount".to_string(), chain::u256_to_value(amount));
+        }
+        outcome.submissions.push(submission);
+        Ok(())
+    }
+    .await;
+
+    Ok(outcome.finish(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `ReserveData` of `fields` words, each holding its own index.
+    fn reserve_data(fields: u64) -> Vec<u8> {
+        let mut output = vec![0; fields as usize * 32];
+        for (index, word) in output.chunks_mut(32).enumerate() {
+            U256::from(index).to_big_endian(word);
+        }
+        output
+    }
+
+    fn debt_token_of(fields: u64, rate_mode: RateMode) -> Address {
+        reserve_debt_token(&reserve_data(fields), rate_mode).unwrap()
+    }
+
+    #[test]
+    fn reads_v2_debt_tokens() {
+        assert_eq!(debt_token_of(12, RateMode::Stable), Address::from_low_u64_be(8));
+        assert_eq!(debt_token_of(12, RateMode::Variable), Address::from_low_u64_be(9));
+    }
+
+    #[test]
+    fn reads_v3_debt_tokens() {
+        assert_eq!(debt_token_of(15, RateMode::Stable), Address::from_low_u64_be(9));
+        assert_eq!(debt_token_of(15, RateMode::Variable), Address::from_low_u64_be(10));
+    }
+
+    #[test]
+    fn rejects_unknown_layouts() {
+        assert!(reserve_debt_token(&reserve_data(11), RateMode::Variable).is_err());
+        assert!(reserve_debt_token(&reserve_data(12)[1..], RateMode::Variable).is_err());
+    }
+}
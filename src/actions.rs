@@ -0,0 +1,893 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::*;
+use serde_json::Value;
+use web3::ethabi::Function;
+use web3::types::{Address, U256};
+
+use crate::aave::{self, RateMode};
+use crate::dag::{
+    self, optional_amount, parse_address, required_field, resolve_amount, transaction_failed,
+    ActionType, NodeData,
+};
+use crate::execution::{ActionOutcome, ErrorKind, ExecutionError};
+use crate::liquidity::{self, Change, LiquidityRequest};
+use crate::router::{self, Route, SwapAmounts};
+use crate::uniswap_v3::{self, V3Path};
+use crate::validation::{self, Field};
+use crate::{chain, contract, erc20, transfer, weth};
+
+/// The outputs every action publishes for its main transaction, besides its own `outputs`.
+pub const TRANSACTION_OUTPUTS: [&str; 3] = ["tx_hash", "gas_used", "status"];
+
+lazy_static::lazy_static! {
+    /// Every action the walker can run, by `action_type`.
+    static ref HANDLERS: HashMap<&'static str, Arc<dyn Handler>> = builtin();
+}
+
+/// What an action node runs with. Amounts written with a unit are already in base units.
+pub struct ActionContext<'a> {
+    pub node_id: u32,
+    pub data: &'a NodeData,
+    pub vars: &'a serde_json::Map<String, serde_json::Value>,
+    pub dry_run: bool,
+}
+
+/// One kind of action node. The walker runs every action the same way: it plans the action,
+/// executes the plan and publishes the outcome's outputs as `$<node id>.<name>`.
+pub trait ActionHandler: Send + Sync + 'static {
+    /// What `plan` works out for `execute`.
+    type Plan: Send;
+
+    /// The fields a node of this action is missing, reported before the zap is saved or run.
+    fn validate(&self, data: &NodeData) -> Vec<&'static str>;
+
+    /// The fields a node of this action sets to values it can't take, with what is wrong with
+    /// each.
+    fn invalid_fields(&self, _data: &NodeData) -> Vec<(&'static str, String)> { Vec::new() }
+
+    /// Resolves the node's inputs and reads whatever the action needs, like a quote, without
+    /// sending anything.
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>>;
+
+    /// Sends the planned transactions, or only simulates them on a dry run.
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>>;
+
+    /// The names of the outputs the action publishes, besides `TRANSACTION_OUTPUTS`.
+    fn outputs(&self) -> &'static [&'static str];
+}
+
+/// An `ActionHandler` as the registry holds it, with its plan kept inside `run`.
+pub trait Handler: Send + Sync {
+    fn validate(&self, data: &NodeData) -> Vec<&'static str>;
+
+    fn invalid_fields(&self, data: &NodeData) -> Vec<(&'static str, String)>;
+
+    fn run<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>>;
+
+    fn outputs(&self) -> &'static [&'static str];
+}
+
+impl<H: ActionHandler> Handler for H {
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> { ActionHandler::validate(self, data) }
+
+    fn invalid_fields(&self, data: &NodeData) -> Vec<(&'static str, String)> {
+        ActionHandler::invalid_fields(self, data)
+    }
+
+    fn run<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let plan = self.plan(context).await?;
+            self.execute(plan, context).await
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { ActionHandler::outputs(self) }
+}
+
+/// An action as listed by the API.
+#[derive(Debug, Serialize, Clone)]
+pub struct ActionInfo {
+    pub action_type: String,
+    pub outputs: Vec<&'static str>,
+}
+
+pub fn handler(action_type: &str) -> Option<Arc<dyn Handler>> {
+    HANDLERS.get(action_type).cloned()
+}
+
+/// Every registered action, sorted by `action_type`.
+pub fn list() -> Vec<ActionInfo> {
+    let mut actions: Vec<ActionInfo> = HANDLERS
+        .iter()
+        .map(|(action_type, handler)| ActionInfo {
+            action_type: action_type.to_string(),
+            outputs: TRANSACTION_OUTPUTS.iter().chain(handler.outputs()).copied().collect(),
+        })
+        .collect();
+    actions.sort_by(|a, b| a.action_type.cmp(&b.action_type));
+
+    actions
+}
+
+/// A handler for every `ActionType`. The match is exhaustive, so a new action type doesn't build
+/// until it has one.
+fn builtin() -> HashMap<&'static str, Arc<dyn Handler>> {
+    ActionType::ALL
+        .iter()
+        .map(|&action_type| {
+            let handler: Arc<dyn Handler> = match action_type {
+                ActionType::SwapExactETHForTokens
+                | ActionType::SwapExactTokensForTokens
+                | ActionType::SwapExactTokensForETH
+                | ActionType::SwapTokensForExactTokens
+                | ActionType::SwapETHForExactTokens
+                | ActionType::SwapTokensForExactETH
+                | ActionType::SwapExactTokensForTokensSupportingFeeOnTransferTokens
+                | ActionType::SwapExactETHForTokensSupportingFeeOnTransferTokens
+                | ActionType::SwapExactTokensForETHSupportingFeeOnTransferTokens => {
+                    Arc::new(Swap(action_type))
+                },
+                ActionType::Approve => Arc::new(Approve),
+                ActionType::TransferETH | ActionType::TransferERC20 => {
+                    Arc::new(Transfer(action_type))
+                },
+                ActionType::WrapETH | ActionType::UnwrapWETH => Arc::new(Weth(action_type)),
+                ActionType::AddLiquidity
+                | ActionType::AddLiquidityETH
+                | ActionType::RemoveLiquidity
+                | ActionType::RemoveLiquidityETH => Arc::new(Liquidity(action_type)),
+                ActionType::V3ExactInputSingle
+                | ActionType::V3ExactInput
+                | ActionType::V3ExactOutputSingle => Arc::new(V3Swap(action_type)),
+                ActionType::ContractCall => Arc::new(ContractCall),
+                ActionType::AaveSupply
+                | ActionType::AaveDeposit
+                | ActionType::AaveWithdraw
+                | ActionType::AaveBorrow
+                | ActionType::AaveRepay => Arc::new(Lending(action_type)),
+            };
+            (action_type.as_str(), handler)
+        })
+        .collect()
+}
+
+/// `APPROVE`: lets `spender`, the router when unset, spend `amount` of `token_address`.
+pub struct Approve;
+
+impl ActionHandler for Approve {
+    type Plan = (String, Option<String>, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        validation::missing_fields(data, &[
+            ("token_address", |d| &d.token_address),
+            ("amount", |d| &d.amount),
+        ])
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (data, node_id) = (context.data, context.node_id);
+            let token_address = required_field(&data.token_address, "token_address", node_id)?;
+            let amount = required_field(&data.amount, "amount", node_id)?;
+            let amount = resolve_amount(amount, context.vars, node_id)?;
+
+            Ok((token_address.to_string(), data.spender.clone(), amount))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (token_address, spender, amount) = plan;
+            erc20::approve(&token_address, spender.as_deref(), amount, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { &["allowance"] }
+}
+
+/// `TRANSFER_ETH` and `TRANSFER_ERC20`: pays `amount` to a `recipient` on the allowlist.
+pub struct Transfer(ActionType);
+
+impl ActionHandler for Transfer {
+    /// The token, none for ETH, the recipient and the amount.
+    type Plan = (Option<Address>, Address, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        let mut missing = validation::missing_fields(data, &[
+            ("recipient", |d| &d.recipient),
+            ("amount", |d| &d.amount),
+        ]);
+        if self.0 == ActionType::TransferERC20 {
+            missing.extend(validation::missing_fields(data, &[("token_address", |d| {
+                &d.token_address
+            })]));
+        }
+
+        missing
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (data, node_id) = (context.data, context.node_id);
+            let recipient = required_field(&data.recipient, "recipient", node_id)?;
+            let recipient = allowed_recipient(recipient, node_id)?;
+            let amount = required_field(&data.amount, "amount", node_id)?;
+            let amount = resolve_amount(amount, context.vars, node_id)?;
+            let token = match self.0 {
+                ActionType::TransferERC20 => {
+                    let token_address =
+                        required_field(&data.token_address, "token_address", node_id)?;
+                    Some(parse_address(token_address, node_id)?)
+                },
+                _ => None,
+            };
+
+            Ok((token, recipient, amount))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let transferred = match plan {
+                (Some(token), recipient, amount) => {
+                    transfer::transfer_erc20(token, recipient, amount, context.dry_run).await
+                },
+                (None, recipient, amount) => {
+                    transfer::transfer_eth(recipient, amount, context.dry_run).await
+                },
+            };
+            transferred.map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        match self.0 {
+            ActionType::TransferERC20 => &["amount", "balance"],
+            _ => &["amount"],
+        }
+    }
+}
+
+/// `WRAP_ETH` and `UNWRAP_WETH`: converts `amount` between ETH and WETH.
+pub struct Weth(ActionType);
+
+impl ActionHandler for Weth {
+    type Plan = U256;
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        validation::missing_fields(data, &[("amount", |d| &d.amount)])
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let amount = required_field(&context.data.amount, "amount", context.node_id)?;
+            resolve_amount(amount, context.vars, context.node_id)
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, amount: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let converted = match self.0 {
+                ActionType::WrapETH => weth::wrap(amount, context.dry_run).await,
+                _ => weth::unwrap(amount, context.dry_run).await,
+            };
+            converted.map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { &["amount", "balance"] }
+}
+
+/// The V2 liquidity actions, adding to or removing from the pool of `token_a_address` and
+/// `token_b_address`, or of `token_a_address` and ETH.
+pub struct Liquidity(ActionType);
+
+impl ActionHandler for Liquidity {
+    /// The request and its slippage in basis points.
+    type Plan = (LiquidityRequest, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        let fields: &[Field] = match self.0 {
+            ActionType::AddLiquidity => &[
+                ("token_a_address", |d| &d.token_a_address),
+                ("token_b_address", |d| &d.token_b_address),
+                ("amount_a_desired", |d| &d.amount_a_desired),
+                ("amount_b_desired", |d| &d.amount_b_desired),
+            ],
+            ActionType::AddLiquidityETH => &[
+                ("token_a_address", |d| &d.token_a_address),
+                ("amount_a_desired", |d| &d.amount_a_desired),
+                ("amount_b_desired", |d| &d.amount_b_desired),
+            ],
+            ActionType::RemoveLiquidity => &[
+                ("token_a_address", |d| &d.token_a_address),
+                ("token_b_address", |d| &d.token_b_address),
+                ("liquidity", |d| &d.liquidity),
+            ],
+            _ => &[("token_a_address", |d| &d.token_a_address), ("liquidity", |d| &d.liquidity)],
+        };
+
+        validation::missing_fields(data, fields)
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let request = liquidity_request(self.0, context.data, context.vars, context.node_id)?;
+            let slippage_bps = slippage_bps(context.data, context.vars, context.node_id)?;
+
+            Ok((request, slippage_bps))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (request, slippage_bps) = plan;
+            liquidity::execute(request, slippage_bps, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["pair", "amount_a", "amount_b", "liquidity", "lp_balance"]
+    }
+}
+
+/// The Uniswap V3 swaps, bounded by their quote loosened by `slippage_bps`.
+pub struct V3Swap(ActionType);
+
+impl ActionHandler for V3Swap {
+    /// The path, the amounts the swap is bounded by and the quote they were derived from.
+    type Plan = (V3Path, SwapAmounts, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        match self.0 {
+            ActionType::V3ExactInput => {
+                let mut missing = validation::missing_fields(data, &[("token_from_amount", |d| {
+                    &d.token_from_amount
+                })]);
+                validation::check_v3_path(data, &mut missing);
+                missing
+            },
+            ActionType::V3ExactOutputSingle => validation::missing_fields(data, &[
+                ("token_from_address", |d| &d.token_from_address),
+                ("token_to_address", |d| &d.token_to_address),
+                ("fee", |d| &d.fee),
+                ("token_to_amount", |d| &d.token_to_amount),
+            ]),
+            _ => validation::missing_fields(data, &[
+                ("token_from_address", |d| &d.token_from_address),
+                ("token_to_address", |d| &d.token_to_address),
+                ("fee", |d| &d.fee),
+                ("token_from_amount", |d| &d.token_from_amount),
+            ]),
+        }
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (data, vars, node_id) = (context.data, context.vars, context.node_id);
+            let path = v3_path(self.0, data, vars, node_id)?;
+            let (amount, limit) = swap_amount(self.0, data, vars, node_id)?;
+            let slippage_bps = slippage_bps(data, vars, node_id)?;
+            let quote = uniswap_v3::quote(self.0, &path, amount)
+                .await
+                .map_err(|e| transaction_failed(node_id, &e))?;
+            let amounts = bound_swap(self.0, amount, quote, limit, slippage_bps, node_id)?;
+
+            Ok((path, amounts, quote))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (path, amounts, quote) = plan;
+            let mut outcome = uniswap_v3::swap(self.0, &path, amounts, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))?;
+            outcome.outputs.insert("quote".to_string(), chain::u256_to_value(quote));
+
+            Ok(outcome)
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { &["amount_in", "amount_out", "quote"] }
+}
+
+/// The Uniswap V2 router swaps, along `path` or the best route the router finds, bounded by
+/// their quote loosened by `slippage_bps`.
+pub struct Swap(ActionType);
+
+impl ActionHandler for Swap {
+    /// The path, the amounts the swap is bounded by and the quote they were derived from.
+    type Plan = (Vec<Address>, SwapAmounts, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        let mut missing = if self.0.is_exact_output() {
+            validation::missing_fields(data, &[("token_to_amount", |d| &d.token_to_amount)])
+        } else {
+            validation::missing_fields(data, &[("token_from_amount", |d| &d.token_from_amount)])
+        };
+        validation::check_swap_path(data, &mut missing);
+
+        missing
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (data, vars, node_id) = (context.data, context.vars, context.node_id);
+            let route = swap_route(data, node_id)?;
+            let (amount, limit) = swap_amount(self.0, data, vars, node_id)?;
+            let slippage_bps = slippage_bps(data, vars, node_id)?;
+            let (path, quote) = router::quote(self.0, &route, amount)
+                .await
+                .map_err(|e| transaction_failed(node_id, &e))?;
+            let amounts = bound_swap(self.0, amount, quote, limit, slippage_bps, node_id)?;
+
+            Ok((path, amounts, quote))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (path, amounts, quote) = plan;
+            let path_value =
+                path.iter().map(|token| Value::String(format!("{:?}", token))).collect();
+            let mut outcome = router::swap(self.0, path, amounts, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))?;
+            outcome.outputs.insert("path".to_string(), Value::Array(path_value));
+            outcome.outputs.insert("quote".to_string(), chain::u256_to_value(quote));
+
+            Ok(outcome)
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { &["amount_in", "amount_out", "path", "quote"] }
+}
+
+/// `CONTRACT_CALL`: calls `function` of `contract_address` with `args`, sending `value` wei.
+pub struct ContractCall;
+
+impl ActionHandler for ContractCall {
+    /// The contract, the function, its encoded arguments and the wei sent along.
+    type Plan = (Address, Function, Vec<u8>, U256);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        let mut missing = validation::missing_fields(data, &[
+            ("contract_address", |d| &d.contract_address),
+            ("function", |d| &d.function),
+        ]);
+        validation::check_abi(data, &mut missing);
+
+        missing
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (address, function, call_data) =
+                dag::contract_call(context.data, context.vars, context.node_id)?;
+            let value = optional_amount(&context.data.value, context.vars, context.node_id)?;
+
+            Ok((address, function, call_data, value.unwrap_or_default()))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (address, function, call_data, value) = plan;
+            contract::call(address, &function, call_data, value, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    /// The function's return values are published under their names, which only its ABI knows.
+    fn outputs(&self) -> &'static [&'static str] { &[] }
+}
+
+/// The Aave lending actions, moving `amount` of `token_address` in or out of the pool at
+/// `pool_address`. Withdrawals and repayments take an `amount` of `max` for everything.
+pub struct Lending(ActionType);
+
+impl Lending {
+    fn takes_max(&self) -> bool {
+        matches!(self.0, ActionType::AaveWithdraw | ActionType::AaveRepay)
+    }
+}
+
+impl ActionHandler for Lending {
+    /// The pool, the asset, the amount and the rate a borrow or repayment is at.
+    type Plan = (Address, Address, U256, RateMode);
+
+    fn validate(&self, data: &NodeData) -> Vec<&'static str> {
+        validation::missing_fields(data, &[
+            ("token_address", |d| &d.token_address),
+            ("amount", |d| &d.amount),
+        ])
+    }
+
+    fn invalid_fields(&self, data: &NodeData) -> Vec<(&'static str, String)> {
+        match data.interest_rate_mode.as_deref().map(str::parse::<RateMode>) {
+            Some(Err(e)) => vec![("interest_rate_mode", format!("{:#}", e))],
+            _ => vec![],
+        }
+    }
+
+    fn plan<'a>(
+        &'a self, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<Self::Plan, ExecutionError>> {
+        Box::pin(async move {
+            let (data, node_id) = (context.data, context.node_id);
+            let pool = dag::pool_address(data, node_id)?;
+            let asset = required_field(&data.token_address, "token_address", node_id)?;
+            let asset = parse_address(asset, node_id)?;
+            let amount = match required_field(&data.amount, "amount", node_id)? {
+                "max" if self.takes_max() => U256::MAX,
+                amount => resolve_amount(amount, context.vars, node_id)?,
+            };
+            let rate_mode = data.interest_rate_mode.as_deref().unwrap_or("variable");
+            let rate_mode = rate_mode.parse().map_err(|e: anyhow::Error| {
+                ExecutionError::new(node_id, ErrorKind::InvalidValue, format!("{:#}", e))
+            })?;
+
+            Ok((pool, asset, amount, rate_mode))
+        })
+    }
+
+    fn execute<'a>(
+        &'a self, plan: Self::Plan, context: &'a ActionContext<'a>,
+    ) -> BoxFuture<'a, Result<ActionOutcome, ExecutionError>> {
+        Box::pin(async move {
+            let (pool, asset, amount, rate_mode) = plan;
+            aave::execute(self.0, pool, asset, amount, rate_mode, context.dry_run)
+                .await
+                .map_err(|e| transaction_failed(context.node_id, &e))
+        })
+    }
+
+    fn outputs(&self) -> &'static [&'static str] { &["asset", "amount"] }
+}
+
+fn allowed_recipient(recipient: &str, node_id: u32) -> Result<Address, ExecutionError> {
+    let recipient = parse_address(recipient, node_id)?;
+    let allowlist = transfer::allowlist().map_err(|e| transaction_failed(node_id, &e))?;
+    if !allowlist.contains(&recipient) {
+        return Err(ExecutionError::new(
+            node_id,
+            ErrorKind::RecipientNotAllowed,
+            format!("{:?} is not on the transfer allowlist", recipient),
+        ));
+    }
+
+    Ok(recipient)
+}
+
+/// A swap goes along `path` when it is set and from `token_from_address` to `token_to_address`
+/// otherwise. With `auto_route` only the ends count and the router picks the hops in between.
+fn swap_route(data: &NodeData, node_id: u32) -> Result<Route, ExecutionError> {
+    let path = match &data.path {
+        Some(path) if !path.is_empty() => {
+            path.iter().map(|token| parse_address(token, node_id)).collect::<Result<Vec<_>, _>>()?
+        },
+        _ => vec![
+            parse_address(
+                required_field(&data.token_from_address, "token_from_address", node_id)?,
+                node_id,
+            )?,
+            parse_address(
+                required_field(&data.token_to_address, "token_to_address", node_id)?,
+                node_id,
+            )?,
+        ],
+    };
+
+    match (path.first(), path.last()) {
+        (Some(from), Some(to)) if path.len() >= 2 => {
+            if data.auto_route == Some(true) {
+                return Ok(Route::Auto { from: *from, to: *to });
+            }
+            Ok(Route::Path(path))
+        },
+        _ => Err(ExecutionError::new(
+            node_id,
+            ErrorKind::MissingNodeData,
+            "path needs at least two tokens",
+        )),
+    }
+}
+
+/// The node's `slippage_bps`, or `SLIPPAGE_BPS` when unset.
+fn slippage_bps(
+    data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<U256, ExecutionError> {
+    let slippage_bps = match optional_amount(&data.slippage_bps, vars, node_id)? {
+        Some(slippage_bps) => slippage_bps,
+        None => router::default_slippage_bps().map(U256::from).map_err(|e| {
+            ExecutionError::new(node_id, ErrorKind::InvalidAmount, format!("{:#}", e))
+        })?,
+    };
+
+    let max_bps = U256::from(10_000);
+    if slippage_bps > max_bps {
+        return Err(ExecutionError::new(
+            node_id,
+            ErrorKind::InvalidAmount,
+            format!("slippage_bps must be at most {}, got {}", max_bps, slippage_bps),
+        ));
+    }
+
+    Ok(slippage_bps)
+}
+
+fn liquidity_request(
+    action_type: ActionType, data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>,
+    node_id: u32,
+) -> Result<LiquidityRequest, ExecutionError> {
+    let token_a = required_field(&data.token_a_address, "token_a_address", node_id)?;
+    let token_b = match action_type {
+        ActionType::AddLiquidityETH | ActionType::RemoveLiquidityETH => None,
+        _ => {
+            let token_b = required_field(&data.token_b_address, "token_b_address", node_id)?;
+            Some(parse_address(token_b, node_id)?)
+        },
+    };
+
+    let change = match action_type {
+        ActionType::AddLiquidity | ActionType::AddLiquidityETH => {
+            let amount_a = required_field(&data.amount_a_desired, "amount_a_desired", node_id)?;
+            let amount_b = required_field(&data.amount_b_desired, "amount_b_desired", node_id)?;
+            Change::Add {
+                amount_a_desired: resolve_amount(amount_a, vars, node_id)?,
+                amount_b_desired: resolve_amount(amount_b, vars, node_id)?,
+            }
+        },
+        _ => {
+            let liquidity = required_field(&data.liquidity, "liquidity", node_id)?;
+            Change::Remove { liquidity: resolve_amount(liquidity, vars, node_id)? }
+        },
+    };
+
+    Ok(LiquidityRequest {
+        token_a: parse_address(token_a, node_id)?,
+        token_b,
+        change,
+        amount_a_min: optional_amount(&data.amount_a_min, vars, node_id)?,
+        amount_b_min: optional_amount(&data.amount_b_min, vars, node_id)?,
+    })
+}
+
+/// The amount a swap fixes, `token_to_amount` for exact-output swaps and `token_from_amount`
+/// otherwise, and the node's absolute limit on the other side if it sets one.
+fn swap_amount(
+    action_type: ActionType, data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>,
+    node_id: u32,
+) -> Result<(U256, Option<U256>), ExecutionError> {
+    if action_type.is_exact_output() {
+        let amount_out = required_field(&data.token_to_amount, "token_to_amount", node_id)?;
+        return Ok((
+            resolve_amount(amount_out, vars, node_id)?,
+            optional_amount(&data.amount_in_max, vars, node_id)?,
+        ));
+    }
+
+    let amount_in = required_field(&data.token_from_amount, "token_from_amount", node_id)?;
+    Ok((
+        resolve_amount(amount_in, vars, node_id)?,
+        optional_amount(&data.amount_out_min, vars, node_id)?,
+    ))
+}
+
+/// Loosens a swap's quote by `slippage_bps` into the bound the router enforces. `amount_out_min`
+/// and `amount_in_max` are absolute limits: a quote beyond them fails the node before anything
+/// is sent, and the derived bound is never looser than them.
+fn bound_swap(
+    action_type: ActionType, amount: U256, quote: U256, limit: Option<U256>, slippage_bps: U256,
+    node_id: u32,
+) -> Result<SwapAmounts, ExecutionError> {
+    let max_bps = U256::from(10_000);
+
+    if action_type.is_exact_output() {
+        // Rounded up, like `amount_out_min` is rounded down, so a small quote keeps its slippage.
+        let loosened = quote.saturating_mul(max_bps + slippage_bps);
+        let mut amount_in_max = loosened / max_bps;
+        if !(loosened % max_bps).is_zero() {
+            amount_in_max = amount_in_max.saturating_add(U256::one());
+        }
+        if let Some(limit) = limit {
+            if quote > limit {
+                return Err(ExecutionError::new(
+                    node_id,
+                    ErrorKind::SlippageExceeded,
+                    format!("quoted input {} is above amount_in_max {}", quote, limit),
+                ));
+            }
+            amount_in_max = std::cmp::min(amount_in_max, limit);
+        }
+
+        return Ok(SwapAmounts::ExactOutput { amount_out: amount, amount_in_max });
+    }
+
+    let mut amount_out_min = quote.saturating_mul(max_bps - slippage_bps) / max_bps;
+    if let Some(limit) = limit {
+        if quote < limit {
+            return Err(ExecutionError::new(
+                node_id,
+                ErrorKind::SlippageExceeded,
+                format!("quoted output {} is below amount_out_min {}", quote, limit),
+            ));
+        }
+        amount_out_min = std::cmp::max(amount_out_min, limit);
+    }
+
+    Ok(SwapAmounts::ExactInput { amount_in: amount, amount_out_min })
+}
+
+/// A V3 swap goes along `path` with one fee tier per hop in `fees`, or from `token_from_address`
+/// to `token_to_address` through the pool of `fee`. The single-pool actions take the latter.
+fn v3_path(
+    action_type: ActionType, data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>,
+    node_id: u32,
+) -> Result<V3Path, ExecutionError> {
+    let multi_hop = action_type == ActionType::V3ExactInput;
+    if let (true, Some(path)) = (multi_hop, data.path.as_ref().filter(|path| !path.is_empty())) {
+        let tokens =
+            path.iter().map(|token| parse_address(token, node_id)).collect::<Result<Vec<_>, _>>()?;
+        let fees = data.fees.clone().unwrap_or_default();
+        if fees.iter().any(|fee| *fee >= uniswap_v3::FEE_DENOMINATOR) {
+            return Err(ExecutionError::new(
+                node_id,
+                ErrorKind::InvalidAmount,
+                format!("fees {:?} are not all valid fee tiers", fees),
+            ));
+        }
+        if tokens.len() < 2 || fees.len() + 1 != tokens.len() {
+            return Err(ExecutionError::new(
+                node_id,
+                ErrorKind::MissingNodeData,
+                "path needs at least two tokens and one fee per hop",
+            ));
+        }
+        return Ok(V3Path { tokens, fees });
+    }
+
+    let token_in = required_field(&data.token_from_address, "token_from_address", node_id)?;
+    let token_out = required_field(&data.token_to_address, "token_to_address", node_id)?;
+    let fee = resolve_amount(required_field(&data.fee, "fee", node_id)?, vars, node_id)?;
+    if fee >= U256::from(uniswap_v3::FEE_DENOMINATOR) {
+        return Err(ExecutionError::new(
+            node_id,
+            ErrorKind::InvalidAmount,
+            format!("fee {} is not a valid fee tier", fee),
+        ));
+    }
+
+    Ok(V3Path {
+        tokens: vec![parse_address(token_in, node_id)?, parse_address(token_out, node_id)?],
+        fees: vec![fee.as_u32()],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const EXACT_INPUT: ActionType = ActionType::SwapExactTokensForTokens;
+    const EXACT_OUTPUT: ActionType = ActionType::SwapTokensForExactTokens;
+
+    fn exact_input(amount_out_min: u64) -> SwapAmounts {
+        SwapAmounts::ExactInput { amount_in: 1_000.into(), amount_out_min: amount_out_min.into() }
+    }
+
+    fn exact_output(amount_in_max: u64) -> SwapAmounts {
+        SwapAmounts::ExactOutput { amount_out: 1_000.into(), amount_in_max: amount_in_max.into() }
+    }
+
+    fn bound(action_type: ActionType, quote: u64, limit: Option<u64>, bps: u64) -> SwapAmounts {
+        bound_swap(action_type, 1_000.into(), quote.into(), limit.map(U256::from), bps.into(), 1)
+            .unwrap()
+    }
+
+    #[test]
+    fn bound_swap_rounds_in_favour_of_the_swap() {
+        assert_eq!(bound(EXACT_INPUT, 199, None, 50), exact_input(198));
+        assert_eq!(bound(EXACT_OUTPUT, 199, None, 50), exact_output(200));
+        assert_eq!(bound(EXACT_INPUT, 1, None, 1), exact_input(0));
+        assert_eq!(bound(EXACT_OUTPUT, 9_999, None, 1), exact_output(10_000));
+    }
+
+    #[test]
+    fn bound_swap_takes_zero_and_full_slippage() {
+        assert_eq!(bound(EXACT_INPUT, 500, None, 0), exact_input(500));
+        assert_eq!(bound(EXACT_OUTPUT, 500, None, 0), exact_output(500));
+        assert_eq!(bound(EXACT_INPUT, 500, None, 10_000), exact_input(0));
+        assert_eq!(
+            bound(EXACT_OUTPUT, 500, None, 10_000),
+            exact_output(1_000)
+        );
+    }
+
+    #[test]
+    fn bound_swap_never_loosens_a_limit() {
+        assert_eq!(bound(EXACT_INPUT, 500, Some(499), 100), exact_input(499));
+        assert_eq!(bound(EXACT_INPUT, 500, Some(400), 100), exact_input(495));
+        assert_eq!(bound(EXACT_OUTPUT, 500, Some(501), 100), exact_output(501));
+        assert_eq!(bound(EXACT_OUTPUT, 500, Some(600), 100), exact_output(505));
+    }
+
+    #[test]
+    fn bound_swap_fails_when_the_quote_is_beyond_the_limit() {
+        let exceeded = |action_type: ActionType, limit: u64| {
+            bound_swap(action_type, 1_000.into(), 500.into(), Some(limit.into()), 0.into(), 1)
+                .unwrap_err()
+                .kind
+        };
+        assert_eq!(exceeded(EXACT_INPUT, 501), ErrorKind::SlippageExceeded);
+        assert_eq!(exceeded(EXACT_OUTPUT, 499), ErrorKind::SlippageExceeded);
+    }
+
+    #[test]
+    fn slippage_bps_is_at_most_everything() {
+        let slippage = |bps: &str| {
+            let data: NodeData = serde_json::from_value(json!({ "slippage_bps": bps })).unwrap();
+            let vars = serde_json::from_value(json!({ "$bps": 10_001 })).unwrap();
+            slippage_bps(&data, &vars, 1)
+        };
+        assert_eq!(slippage("0").unwrap(), U256::zero());
+        assert_eq!(slippage("10000").unwrap(), U256::from(10_000));
+        assert_eq!(slippage("10001").unwrap_err().kind, ErrorKind::InvalidAmount);
+        assert_eq!(slippage("$bps").unwrap_err().kind, ErrorKind::InvalidAmount);
+    }
+
+    #[test]
+    fn every_action_type_has_a_handler() {
+        for action_type in ActionType::ALL {
+            assert!(handler(action_type.as_str()).is_some(), "{:?} has no handler", action_type);
+            assert_eq!(serde_json::to_value(action_type).unwrap(), json!(action_type.as_str()));
+        }
+        assert_eq!(list().len(), ActionType::ALL.len());
+    }
+}
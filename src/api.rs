@@ -1,69 +1,336 @@
-use std::{env, fs};
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use anyhow::Context;
+use serde::Deserialize;
+use uuid::Uuid;
 
-use actix_web::{get, post, put, web, HttpResponse, Responder};
+use crate::execution::Journal;
+use crate::store::ZapStore;
+use crate::zap::{Zap, ZapInput, DEFAULT_ZAP_ID};
+use crate::{actions, dag, diff, execution, validation};
 
-use crate::dag;
+/// Runs `f` against the store on actix's blocking thread pool, so file and database I/O doesn't
+/// stall the worker handling requests.
+async fn blocking<T, F>(store: &web::Data<dyn ZapStore>, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn ZapStore) -> anyhow::Result<T> + Send + 'static,
+{
+    let store = store.clone();
+    web::block(move || f(&**store)).await.context("Store call was cancelled")?
+}
 
+/// The graph of the default zap, which `PUT /` saves.
 #[get("/")]
-pub async fn get_dag() -> impl Responder {
-    let data_file_path = env::var("DATA_FILE_PATH").expect("DATA_FILE_PATH must be set");
-    let data = fs::read(data_file_path);
-    if data.is_err() {
-        return HttpResponse::BadRequest().body("can't read data");
+pub async fn get_dag(store: web::Data<dyn ZapStore>) -> impl Responder {
+    match blocking(&store, |store| store.get(DEFAULT_ZAP_ID)).await {
+        Ok(Some(zap)) => HttpResponse::Ok().json(zap.nodes),
+        Ok(None) => HttpResponse::NotFound().body("no graph saved"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e)),
     }
+}
 
-    let json = serde_json::from_slice::<Vec<dag::Node>>(&data.unwrap());
-    if json.is_err() {
-        return HttpResponse::BadRequest().body("invalid json");
+/// Saves the graph as a new version of the default zap, creating it on the first save.
+#[put("/")]
+pub async fn update_dag(
+    store: web::Data<dyn ZapStore>, json: web::Json<Vec<dag::Node>>,
+) -> impl Responder {
+    let problems = validation::validate(&json);
+    if !problems.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(problems);
     }
 
-    HttpResponse::Ok().json(json.unwrap())
+    let nodes = json.into_inner();
+    let saved = blocking(&store, move |store| {
+        let (zap, version) = match store.get(DEFAULT_ZAP_ID)? {
+            Some(mut zap) => {
+                let version = zap.set_graph(nodes);
+                (zap, version)
+            },
+            None => Zap::new_default(nodes),
+        };
+        store.save(&zap, &version).map(|()| zap.nodes)
+    })
+    .await;
+
+    match saved {
+        Ok(nodes) => HttpResponse::Ok().json(nodes),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error saving zap: {:#}", e)),
+    }
 }
 
-#[put("/")]
-pub async fn update_dag(json: web::Json<Vec<dag::Node>>) -> impl Responder {
-    let data_file_path = env::var("DATA_FILE_PATH").expect("DATA_FILE_PATH must be set");
+#[post("/validate")]
+pub async fn validate_dag(json: web::Json<Vec<dag::Node>>) -> impl Responder {
+    HttpResponse::Ok().json(validation::Report::from(validation::validate(&json)))
+}
 
-    let json_string = serde_json::to_string(&json);
-    if json_string.is_err() {
-        return HttpResponse::BadRequest().body("Invalid JSON");
+#[derive(Debug, Deserialize)]
+pub struct PlayQuery {
+    trigger: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Runs the default zap.
+#[post("/play")]
+pub async fn play(
+    store: web::Data<dyn ZapStore>, query: web::Query<PlayQuery>,
+    inputs: Option<web::Json<serde_json::Map<String, serde_json::Value>>>,
+) -> impl Responder {
+    let inputs = inputs.map(web::Json::into_inner).unwrap_or_default();
+    match blocking(&store, |store| store.get(DEFAULT_ZAP_ID)).await {
+        Ok(Some(zap)) => {
+            run(&store, Some(zap.id), zap.nodes, query.into_inner(), inputs).await
+        },
+        Ok(None) => HttpResponse::NotFound().body("no graph saved"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e)),
     }
+}
+
+/// Stores a new run as `RUNNING` and walks `nodes` from `inputs` in a task of its own, so a client
+/// that disconnects can't abort a half-executed strategy. Every transaction is saved as soon as it
+/// is broadcast, and the finished report replaces the running one. Responds with the running
+/// report, whose id `GET /runs/{id}` follows.
+async fn run(
+    store: &web::Data<dyn ZapStore>, zap_id: Option<Uuid>, nodes: Vec<dag::Node>,
+    query: PlayQuery, inputs: serde_json::Map<String, serde_json::Value>,
+) -> HttpResponse {
+    let (dag, rindex) = match dag::parse(nodes) {
+        Ok(parsed) => parsed,
+        Err(problems) => return HttpResponse::UnprocessableEntity().json(problems),
+    };
 
-    let result = fs::write(data_file_path, json_string.unwrap());
-    if let Err(e) = result {
-        return HttpResponse::InternalServerError().body(format!("Error writing to file: {}", e));
+    let trigger = query.trigger.unwrap_or_else(|| "api".to_string());
+    let mut report = execution::RunReport::new(zap_id, trigger, query.dry_run, inputs.clone());
+    let store = store.clone();
+    let journal = Journal::new(&report, move |report| {
+        let store = store.clone();
+        Box::pin(async move { blocking(&store, move |store| store.save_run(&report)).await })
+    });
+    if let Err(e) = journal.save(&report).await {
+        return HttpResponse::InternalServerError().body(format!("Error saving run: {:#}", e));
     }
 
-    HttpResponse::Ok().json(json)
+    let running = report.clone();
+    actix_rt::spawn(journal.clone().scope(async move {
+        let result = dag::walk(&dag, rindex, inputs, &mut report).await;
+        report.finish(result);
+        if let Err(e) = journal.save(&report).await {
+            log::error!("Failed to save run {}: {:#}", report.id, e);
+        }
+    }));
+
+    HttpResponse::Accepted().json(running)
 }
 
-#[post("/play")]
-pub async fn play() -> impl Responder {
-    let data_file_path = env::var("DATA_FILE_PATH").expect("DATA_FILE_PATH must be set");
-    let data = fs::read(data_file_path);
-    if data.is_err() {
-        return HttpResponse::BadRequest().body("can't read data");
+#[post("/zaps")]
+pub async fn create_zap(
+    store: web::Data<dyn ZapStore>, json: web::Json<ZapInput>,
+) -> impl Responder {
+    let problems = validation::validate(&json.nodes);
+    if !problems.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(problems);
     }
 
-    let json = serde_json::from_slice::<Vec<dag::Node>>(&data.unwrap());
-    if json.is_err() {
-        return HttpResponse::BadRequest().body("invalid json");
+    let (zap, version) = Zap::new(json.into_inner());
+    match blocking(&store, move |store| store.save(&zap, &version).map(|()| zap)).await {
+        Ok(zap) => HttpResponse::Created().json(zap),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error saving zap: {:#}", e)),
     }
+}
 
-    let (dag, rindex) = dag::parse(json.unwrap());
-    if rindex.is_none() {
-        return HttpResponse::BadRequest().body("invalid json: root is not exist");
+#[get("/zaps")]
+pub async fn list_zaps(store: web::Data<dyn ZapStore>) -> impl Responder {
+    match blocking(&store, |store| store.list()).await {
+        Ok(zaps) => HttpResponse::Ok().json(zaps),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading zaps: {:#}", e)),
     }
+}
+
+#[get("/zaps/{id}")]
+pub async fn get_zap(store: web::Data<dyn ZapStore>, id: web::Path<Uuid>) -> impl Responder {
+    let id = id.into_inner();
+    match blocking(&store, move |store| store.get(id)).await {
+        Ok(Some(zap)) => HttpResponse::Ok().json(zap),
+        Ok(None) => HttpResponse::NotFound().body("zap not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e)),
+    }
+}
+
+#[put("/zaps/{id}")]
+pub async fn update_zap(
+    store: web::Data<dyn ZapStore>, id: web::Path<Uuid>, json: web::Json<ZapInput>,
+) -> impl Responder {
+    let problems = validation::validate(&json.nodes);
+    if !problems.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(problems);
+    }
+
+    let id = id.into_inner();
+    let mut zap = match blocking(&store, move |store| store.get(id)).await {
+        Ok(Some(zap)) => zap,
+        Ok(None) => return HttpResponse::NotFound().body("zap not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e))
+        },
+    };
 
-    let vars = serde_json::Map::new();
-    dag::walk(dag, rindex.unwrap(), vars);
+    let version = zap.update(json.into_inner());
+    match blocking(&store, move |store| store.save(&zap, &version).map(|()| zap)).await {
+        Ok(zap) => HttpResponse::Ok().json(zap),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error saving zap: {:#}", e)),
+    }
+}
 
-    // dag::swap_exact_eth_for_tokens(
-    //     "0xdac17f958d2ee523a2206206994597c13d831ec7".to_string(),
-    //     "0x4DF812F6064def1e5e029f1ca858777CC98D2D81".to_string(),
-    //     106662000000,
-    // )
-    //     .await;
+#[delete("/zaps/{id}")]
+pub async fn delete_zap(store: web::Data<dyn ZapStore>, id: web::Path<Uuid>) -> impl Responder {
+    let id = id.into_inner();
+    match blocking(&store, move |store| store.delete(id)).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("zap not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error deleting zap: {:#}", e)),
+    }
+}
 
-    HttpResponse::Ok().json("ok")
+#[post("/zaps/{id}/play")]
+pub async fn play_zap(
+    store: web::Data<dyn ZapStore>, id: web::Path<Uuid>, query: web::Query<PlayQuery>,
+    inputs: Option<web::Json<serde_json::Map<String, serde_json::Value>>>,
+) -> impl Responder {
+    let inputs = inputs.map(web::Json::into_inner).unwrap_or_default();
+    let id = id.into_inner();
+    match blocking(&store, move |store| store.get(id)).await {
+        Ok(Some(zap)) => {
+            run(&store, Some(zap.id), zap.nodes, query.into_inner(), inputs).await
+        },
+        Ok(None) => HttpResponse::NotFound().body("zap not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e)),
+    }
 }
+
+#[get("/zaps/{id}/versions")]
+pub async fn list_zap_versions(
+    store: web::Data<dyn ZapStore>, id: web::Path<Uuid>,
+) -> impl Responder {
+    let id = id.into_inner();
+    match blocking(&store, move |store| store.get(id)).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return HttpResponse::NotFound().body("zap not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e))
+        },
+    }
+
+    match blocking(&store, move |store| store.list_versions(id)).await {
+        Ok(versions) => HttpResponse::Ok().json(versions),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error reading versions: {:#}", e))
+        },
+    }
+}
+
+#[get("/zaps/{id}/versions/{version}")]
+pub async fn get_zap_version(
+    store: web::Data<dyn ZapStore>, path: web::Path<(Uuid, u32)>,
+) -> impl Responder {
+    let (id, version) = path.into_inner();
+    match blocking(&store, move |store| store.get_version(id, version)).await {
+        Ok(Some(version)) => HttpResponse::Ok().json(version),
+        Ok(None) => HttpResponse::NotFound().body("version not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error reading version: {:#}", e))
+        },
+    }
+}
+
+#[get("/zaps/{id}/versions/{from}/diff/{to}")]
+pub async fn diff_zap_versions(
+    store: web::Data<dyn ZapStore>, path: web::Path<(Uuid, u32, u32)>,
+) -> impl Responder {
+    let (id, from, to) = path.into_inner();
+    let mut versions = Vec::new();
+    for version in [from, to] {
+        match blocking(&store, move |store| store.get_version(id, version)).await {
+            Ok(Some(version)) => versions.push(version),
+            Ok(None) => {
+                return HttpResponse::NotFound().body(format!("version {} not found", version))
+            },
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error reading version: {:#}", e))
+            },
+        }
+    }
+
+    HttpResponse::Ok().json(diff::diff(&versions[0].nodes, &versions[1].nodes))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackQuery {
+    author: Option<String>,
+}
+
+#[post("/zaps/{id}/rollback/{version}")]
+pub async fn rollback_zap(
+    store: web::Data<dyn ZapStore>, path: web::Path<(Uuid, u32)>,
+    query: web::Query<RollbackQuery>,
+) -> impl Responder {
+    let (id, version) = path.into_inner();
+    let mut zap = match blocking(&store, move |store| store.get(id)).await {
+        Ok(Some(zap)) => zap,
+        Ok(None) => return HttpResponse::NotFound().body("zap not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Error reading zap: {:#}", e))
+        },
+    };
+
+    let target = match blocking(&store, move |store| store.get_version(id, version)).await {
+        Ok(Some(target)) => target,
+        Ok(None) => return HttpResponse::NotFound().body("version not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error reading version: {:#}", e))
+        },
+    };
+
+    let problems = validation::validate(&target.nodes);
+    if !problems.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(problems);
+    }
+
+    let version = zap.rollback(&target, query.into_inner().author);
+    match blocking(&store, move |store| store.save(&zap, &version).map(|()| zap)).await {
+        Ok(zap) => HttpResponse::Ok().json(zap),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error saving zap: {:#}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunsQuery {
+    zap_id: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+#[get("/runs")]
+pub async fn list_runs(
+    store: web::Data<dyn ZapStore>, query: web::Query<RunsQuery>,
+) -> impl Responder {
+    let RunsQuery { zap_id, limit } = query.into_inner();
+    match blocking(&store, move |store| store.list_runs(zap_id, limit.unwrap_or(50))).await {
+        Ok(runs) => HttpResponse::Ok().json(runs),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading runs: {:#}", e)),
+    }
+}
+
+#[get("/runs/{id}")]
+pub async fn get_run(store: web::Data<dyn ZapStore>, id: web::Path<Uuid>) -> impl Responder {
+    let id = id.into_inner();
+    match blocking(&store, move |store| store.get_run(id)).await {
+        Ok(Some(run)) => HttpResponse::Ok().json(run),
+        Ok(None) => HttpResponse::NotFound().body("run not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading run: {:#}", e)),
+    }
+}
+
+/// Every action type a node can use, with the outputs it publishes.
+#[get("/actions")]
+pub async fn list_actions() -> impl Responder { HttpResponse::Ok().json(actions::list()) }
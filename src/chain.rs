@@ -0,0 +1,367 @@
+use std::{env, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use secp256k1::SecretKey;
+use serde_json::{Map, Number, Value};
+use web3::{
+    ethabi::{self, ParamType, Token},
+    transports::Http,
+    types::{
+        Address, BlockId, BlockNumber, Bytes, CallRequest, TransactionId, TransactionParameters,
+        TransactionReceipt, H256, U256, U64,
+    },
+    Web3,
+};
+
+use crate::execution::{self, Submission};
+use crate::nonce::NONCES;
+
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// `bytes4(keccak256("Error(string)"))`
+const ERROR_STRING_SELECTOR: &[u8] = &[0x08, 0xc3, 0x79, 0xa0];
+/// `bytes4(keccak256("Panic(uint256)"))`
+const PANIC_SELECTOR: &[u8] = &[0x4e, 0x48, 0x7b, 0x71];
+
+pub fn connect() -> anyhow::Result<Web3<Http>> {
+    let provider_url = env::var("PROVIDER_URL").context("PROVIDER_URL must be set")?;
+    Ok(Web3::new(Http::new(&provider_url)?))
+}
+
+/// The address `variable` configures for `chain_id`, if any. The variable holds comma separated
+/// `<chain id>=<address>` entries.
+pub fn configured_address(variable: &str, chain_id: u64) -> anyhow::Result<Option<Address>> {
+    let configured = match env::var(variable) {
+        Ok(configured) => configured,
+        Err(_) => return Ok(None),
+    };
+
+    for entry in configured.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (id, address) = entry
+            .split_once('=')
+            .with_context(|| format!("Invalid {} entry {}", variable, entry))?;
+        let id: u64 = id.trim().parse().with_context(|| format!("Invalid chain id in {}", entry))?;
+        if id == chain_id {
+            return Address::from_str(address.trim())
+                .map(Some)
+                .with_context(|| format!("Invalid address in {} entry {}", variable, entry));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The address every action signs and sends from.
+pub fn account() -> anyhow::Result<Address> {
+    let account_address = env::var("ACCOUNT_ADDRESS").context("ACCOUNT_ADDRESS must be set")?;
+    Address::from_str(&account_address).context("Invalid account address")
+}
+
+/// Builds a transaction from the signing account to `to`, with the node's gas estimate and the
+/// current gas price. A call that would revert fails here with the decoded reason.
+pub async fn transaction(
+    web3: &Web3<Http>, to: Address, value: U256, data: Vec<u8>,
+) -> anyhow::Result<TransactionParameters> {
+    let request = CallRequest {
+        from: Some(account()?),
+        to: Some(to),
+        value: Some(value),
+        data: Some(Bytes(data.clone())),
+        ..Default::default()
+    };
+    let gas = web3.eth().estimate_gas(request, None).await.map_err(|e| match revert_data(&e) {
+        Some(data) => anyhow::anyhow!("Gas estimation reverted: {}", decode_revert(&data)),
+        None => anyhow::Error::new(e).context("Failed to estimate gas"),
+    })?;
+    let gas_price = web3.eth().gas_price().await.context("Failed to get gas price")?;
+
+    Ok(TransactionParameters {
+        to: Some(to),
+        value,
+        gas,
+        gas_price: Some(gas_price),
+        data: Bytes(data),
+        ..Default::default()
+    })
+}
+
+/// Sends `parameters`, or in a dry run only simulates them and hands back the unsent transaction.
+pub async fn submit(
+    web3: &Web3<Http>, parameters: TransactionParameters, dry_run: bool,
+) -> anyhow::Result<Submission> {
+    if dry_run {
+        simulate(web3, account()?, &parameters).await?;
+        return Ok(Submission::unsent(parameters));
+    }
+
+    send(web3, parameters).await
+}
+
+/// The outputs every action publishes for its main transaction: `gas_used` and `status`, plus
+/// `tx_hash` once sent. A dry run reports the gas estimate and assumes success, and a transaction
+/// that was never confirmed only has its hash.
+pub fn submission_outputs(submission: &Submission) -> Map<String, Value> {
+    let mut outputs = Map::new();
+    if let Some(hash) = submission.hash {
+        outputs.insert("tx_hash".to_string(), Value::String(format!("{:?}", hash)));
+    }
+    match &submission.receipt {
+        Some(receipt) => {
+            outputs.insert(
+                "gas_used".to_string(),
+                u256_to_value(receipt.gas_used.unwrap_or_default()),
+            );
+            outputs.insert(
+                "status".to_string(),
+                Value::from(receipt.status.map_or(0, |status| status.as_u64())),
+            );
+        },
+        None if submission.hash.is_some() => {},
+        None => {
+            outputs.insert("gas_used".to_string(), u256_to_value(submission.parameters.gas));
+            outputs.insert("status".to_string(), Value::from(1));
+        },
+    }
+
+    outputs
+}
+
+/// Signs `parameters` with `PRIVATE_KEY` under the next free nonce of the account, broadcasts
+/// the transaction and waits until it has `CONFIRMATIONS` blocks on top. If it reverted, the call
+/// is replayed to find out why. Once broadcast, the transaction is returned even if its receipt
+/// never arrives, so the run still records its hash.
+pub async fn send(
+    web3: &Web3<Http>, mut parameters: TransactionParameters,
+) -> anyhow::Result<Submission> {
+    let private_key_string = env::var("PRIVATE_KEY").context("PRIVATE_KEY must be set")?;
+    let private_key: SecretKey = private_key_string.parse().context("Invalid private key")?;
+    let account = account()?;
+    let confirmations = confirmations()?;
+
+    let reservation = NONCES.reserve(web3, account).await.context("Failed to get nonce")?;
+    let nonce = reservation.nonce;
+    parameters.nonce = Some(nonce);
+
+    let hash = match sign_and_send(web3, parameters.clone(), &private_key).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            drop(reservation);
+            if let Err(resync_error) = NONCES.resync(web3, account).await {
+                log::error!("Failed to resync nonce of {:?}: {}", account, resync_error);
+            }
+            return Err(e);
+        },
+    };
+
+    log::info!("Transaction sent with hash: {:?}", hash);
+    reservation.sent(hash);
+    let sent = Submission { hash: Some(hash), ..Submission::unsent(parameters.clone()) };
+    execution::record_sent(&sent).await;
+
+    let receipt = match wait_for_receipt(web3, hash, confirmations).await {
+        Ok(receipt) => {
+            NONCES.mined(account, nonce);
+            receipt
+        },
+        Err(e) => {
+            // A transaction the node no longer knows about was dropped, so its nonce is free.
+            if let Ok(None) = web3.eth().transaction(TransactionId::Hash(hash)).await {
+                log::warn!("Transaction {:?} was dropped, releasing nonce {}", hash, nonce);
+                NONCES.release(account, nonce);
+            }
+            log::warn!("Transaction {:?} was sent but not confirmed: {:#}", hash, e);
+            return Ok(Submission { hash: Some(hash), ..Submission::unsent(parameters) });
+        },
+    };
+
+    let mut revert_reason = None;
+    if receipt.status == Some(U64::zero()) {
+        let reason = replay(web3, account, &parameters, receipt.block_number).await;
+        log::warn!("Transaction {:?} reverted: {}", hash, reason);
+        revert_reason = Some(reason);
+    }
+
+    Ok(Submission { parameters, hash: Some(hash), receipt: Some(receipt), revert_reason })
+}
+
+async fn sign_and_send(
+    web3: &Web3<Http>, parameters: TransactionParameters, private_key: &SecretKey,
+) -> anyhow::Result<H256> {
+    let signed_transaction = web3
+        .accounts()
+        .sign_transaction(parameters, private_key)
+        .await
+        .context("Failed to sign transaction")?;
+
+    web3.eth()
+        .send_raw_transaction(signed_transaction.raw_transaction)
+        .await
+        .context("Failed to send transaction")
+}
+
+/// How many blocks must be mined on top of a transaction's block before it counts as final.
+/// Defaults to 0, which suits a local node that only mines when it receives a transaction.
+fn confirmations() -> anyhow::Result<u64> {
+    match env::var("CONFIRMATIONS") {
+        Ok(confirmations) => confirmations.parse().context("Invalid CONFIRMATIONS"),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Polls until the transaction is mined with `confirmations` blocks on top. The receipt is
+/// fetched again on every poll, so a reorg that moves the transaction restarts the count.
+async fn wait_for_receipt(
+    web3: &Web3<Http>, hash: H256, confirmations: u64,
+) -> anyhow::Result<TransactionReceipt> {
+    let wait = async {
+        loop {
+            if let Some(receipt) = web3.eth().transaction_receipt(hash).await? {
+                if let Some(block_number) = receipt.block_number {
+                    let head = web3.eth().block_number().await?;
+                    if head >= block_number + confirmations {
+                        return Ok::<_, web3::Error>(receipt);
+                    }
+                }
+            }
+            tokio::time::sleep(RECEIPT_POLL_INTERVAL).await;
+        }
+    };
+
+    tokio::time::timeout(RECEIPT_TIMEOUT, wait)
+        .await
+        .with_context(|| format!("Timed out waiting for the receipt of {:?}", hash))?
+        .with_context(|| format!("Failed to get the receipt of {:?}", hash))
+}
+
+fn call_request(from: Address, parameters: &TransactionParameters) -> CallRequest {
+    CallRequest {
+        from: Some(from),
+        to: parameters.to,
+        gas: Some(parameters.gas),
+        gas_price: parameters.gas_price,
+        value: Some(parameters.value),
+        data: Some(parameters.data.clone()),
+        ..Default::default()
+    }
+}
+
+/// Runs `parameters` through `eth_call` from `from` and returns what the call would return. A
+/// revert comes back as an error carrying the decoded reason.
+pub async fn simulate(
+    web3: &Web3<Http>, from: Address, parameters: &TransactionParameters,
+) -> anyhow::Result<Bytes> {
+    web3.eth().call(call_request(from, parameters), None).await.map_err(|e| match revert_data(&e) {
+        Some(data) => anyhow::anyhow!("Simulation reverted: {}", decode_revert(&data)),
+        None => anyhow::Error::new(e).context("Failed to simulate transaction"),
+    })
+}
+
+/// Runs a read-only call of `data` on `to` through `eth_call` against the latest block. A revert
+/// comes back as an error carrying the decoded reason.
+pub async fn call(web3: &Web3<Http>, to: Address, data: Vec<u8>) -> anyhow::Result<Bytes> {
+    let request = CallRequest {
+        from: account().ok(),
+        to: Some(to),
+        data: Some(Bytes(data)),
+        ..Default::default()
+    };
+    web3.eth().call(request, None).await.map_err(|e| match revert_data(&e) {
+        Some(data) => anyhow::anyhow!("Call reverted: {}", decode_revert(&data)),
+        None => anyhow::Error::new(e).context("Failed to call contract"),
+    })
+}
+
+/// The timestamp of the latest block.
+pub async fn block_timestamp(web3: &Web3<Http>) -> anyhow::Result<U256> {
+    let block = web3
+        .eth()
+        .block(BlockId::Number(BlockNumber::Latest))
+        .await
+        .context("Failed to get the latest block")?
+        .context("The node has no latest block")?;
+    Ok(block.timestamp)
+}
+
+/// The wei `address` holds at the latest block.
+pub async fn balance(web3: &Web3<Http>, address: Address) -> anyhow::Result<U256> {
+    web3.eth()
+        .balance(address, None)
+        .await
+        .with_context(|| format!("Failed to get the balance of {:?}", address))
+}
+
+/// Re-executes a reverted transaction with `eth_call` in the block it failed in, since receipts
+/// don't carry the revert data.
+async fn replay(
+    web3: &Web3<Http>, from: Address, parameters: &TransactionParameters,
+    block_number: Option<U64>,
+) -> String {
+    let block = block_number.map(|number| BlockId::Number(BlockNumber::Number(number)));
+    match web3.eth().call(call_request(from, parameters), block).await {
+        Ok(_) => "reverted, but succeeds when replayed".to_string(),
+        Err(e) => match revert_data(&e) {
+            Some(data) => decode_revert(&data),
+            None => format!("reverted, replay failed: {}", e),
+        },
+    }
+}
+
+/// Pulls the revert data out of an `eth_call` error. Nodes put it in the error's `data` field,
+/// either as a hex string or wrapped in an object.
+fn revert_data(error: &web3::Error) -> Option<Vec<u8>> {
+    let rpc_error = match error {
+        web3::Error::Rpc(rpc_error) => rpc_error,
+        _ => return None,
+    };
+
+    let hex = match &rpc_error.data {
+        Some(Value::String(hex)) => hex.clone(),
+        Some(Value::Object(object)) => object.get("data")?.as_str()?.to_string(),
+        _ if rpc_error.message.contains("revert") => return Some(Vec::new()),
+        _ => return None,
+    };
+
+    let hex = hex.trim_start_matches("0x");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Turns revert data into something readable: the message of `Error(string)`, the code of
+/// `Panic(uint256)`, or the selector and raw arguments of a custom error.
+pub fn decode_revert(data: &[u8]) -> String {
+    if data.len() < 4 {
+        return "reverted without a reason".to_string();
+    }
+
+    let (selector, arguments) = data.split_at(4);
+    let decoded = match selector {
+        ERROR_STRING_SELECTOR => ethabi::decode(&[ParamType::String], arguments)
+            .ok()
+            .and_then(|tokens| tokens.into_iter().next())
+            .and_then(Token::into_string),
+        PANIC_SELECTOR => ethabi::decode(&[ParamType::Uint(256)], arguments)
+            .ok()
+            .and_then(|tokens| tokens.into_iter().next())
+            .and_then(Token::into_uint)
+            .map(|code| format!("panic 0x{:x}", code)),
+        _ => None,
+    };
+
+    decoded.unwrap_or_else(|| {
+        format!("custom error 0x{} with data 0x{}", to_hex(selector), to_hex(arguments))
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() }
+
+/// JSON numbers only go up to `u64`, so anything larger is published as a decimal string.
+pub fn u256_to_value(value: U256) -> Value {
+    if value <= U256::from(u64::MAX) {
+        return Value::Number(Number::from(value.as_u64()));
+    }
+
+    Value::String(value.to_string())
+}
@@ -0,0 +1,371 @@
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde_json::Value;
+use web3::ethabi::{self, Function, ParamType, Token};
+use web3::types::{Address, Bytes, U256};
+
+use crate::chain;
+use crate::execution::{ActionOutcome, Submission};
+
+/// The ABIs shipped with the binary, which `abi_name` can refer to without any setup.
+const BUNDLED_ABIS: [(&str, &[u8]); 10] = [
+    ("erc20", include_bytes!("./erc20_abi.json")),
+    ("weth", include_bytes!("./weth_abi.json")),
+    ("uniswap_v2_router02", include_bytes!("./router02_abi.json")),
+    ("uniswap_v2_factory", include_bytes!("./factory_abi.json")),
+    ("uniswap_v2_pair", include_bytes!("./pair_abi.json")),
+    ("uniswap_v3_swap_router", include_bytes!("./swap_router_abi.json")),
+    ("uniswap_v3_quoter", include_bytes!("./quoter_abi.json")),
+    ("chainlink_aggregator", include_bytes!("./aggregator_abi.json")),
+    ("multicall3", include_bytes!("./multicall3_abi.json")),
+    ("aave_pool", include_bytes!("./aave_pool_abi.json")),
+];
+
+/// Where the ABI of a contract call comes from.
+pub enum AbiSource<'a> {
+    /// The ABI itself, as a JSON array or a string holding one.
+    Inline(&'a Value),
+    /// The name of an ABI in the registry.
+    Registry(&'a str),
+}
+
+/// Parses the ABI of `source`.
+pub fn abi(source: AbiSource<'_>) -> anyhow::Result<ethabi::Contract> {
+    match source {
+        AbiSource::Inline(Value::String(json)) => {
+            serde_json::from_str(json).context("Invalid inline ABI")
+        },
+        AbiSource::Inline(value) => {
+            serde_json::from_value(value.clone()).context("Invalid inline ABI")
+        },
+        AbiSource::Registry(name) => registered(name),
+    }
+}
+
+/// Looks `name` up in the registry: `<name>.json` in `ABI_DIR_PATH` when that directory has it,
+/// one of the bundled ABIs otherwise.
+fn registered(name: &str) -> anyhow::Result<ethabi::Contract> {
+    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if name.is_empty() || !valid_name {
+        anyhow::bail!("Invalid ABI name {:?}", name);
+    }
+
+    if let Ok(dir_path) = env::var("ABI_DIR_PATH") {
+        let file_path = Path::new(&dir_path).join(format!("{}.json", name));
+        if file_path.is_file() {
+            let file = std::fs::File::open(&file_path)
+                .with_context(|| format!("Failed to open {}", file_path.display()))?;
+            return ethabi::Contract::load(file)
+                .with_context(|| format!("Invalid ABI in {}", file_path.display()));
+        }
+    }
+
+    let (_, json) = BUNDLED_ABIS
+        .iter()
+        .find(|(bundled, _)| *bundled == name)
+        .with_context(|| format!("No ABI named {} in the registry", name))?;
+    ethabi::Contract::load(*json).with_context(|| format!("Invalid bundled ABI {}", name))
+}
+
+/// Finds `signature` in `abi`. A bare name like `deposit` must not be overloaded, a full
+/// signature like `transfer(address,uint256)` picks the overload with those parameter types.
+pub fn function<'a>(abi: &'a ethabi::Contract, signature: &str) -> anyhow::Result<&'a Function> {
+    let signature: String = signature.chars().filter(|c| !c.is_whitespace()).collect();
+    let name = signature.split('(').next().unwrap_or_default();
+    let overloads = abi
+        .functions_by_name(name)
+        .map_err(|_| anyhow::anyhow!("The ABI has no function {}", name))?;
+
+    if !signature.contains('(') {
+        return match overloads.as_slice() {
+            [function] => Ok(function),
+            _ => anyhow::bail!("{} is overloaded, give its full signature", name),
+        };
+    }
+
+    overloads
+        .iter()
+        .find(|function| canonical_signature(function) == signature)
+        .with_context(|| format!("The ABI has no function {}", signature))
+}
+
+/// `name(type,...)` as the function selector is derived from it.
+fn canonical_signature(function: &Function) -> String {
+    let types: Vec<String> = function
+        .inputs
+        .iter()
+        .map(|param| ethabi::param_type::Writer::write(&param.kind))
+        .collect();
+    format!("{}({})", function.name, types.join(","))
+}
+
+/// Encodes a call of `function` with `args`, one JSON value per parameter.
+pub fn encode(function: &Function, args: &[Value]) -> anyhow::Result<Vec<u8>> {
+    if args.len() != function.inputs.len() {
+        anyhow::bail!(
+            "{} takes {} arguments, got {}",
+            canonical_signature(function),
+            function.inputs.len(),
+            args.len()
+        );
+    }
+
+    let tokens = function
+        .inputs
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| {
+            token(&param.kind, arg).with_context(|| format!("Invalid argument {}", param.name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    function.encode_input(&tokens).context("Failed to encode call")
+}
+
+/// Converts a JSON argument to the ABI type it is passed as. Integers may be JSON numbers or
+/// decimal strings, bytes are `0x` prefixed hex, and arrays and tuples are JSON arrays.
+fn token(kind: &ParamType, value: &Value) -> anyhow::Result<Token> {
+    let mismatch = || anyhow::anyhow!("{} is not a valid {}", value, kind);
+
+    let token = match (kind, value) {
+        (ParamType::Address, Value::String(address)) => {
+            Token::Address(Address::from_str(address).map_err(|_| mismatch())?)
+        },
+        (ParamType::Uint(bits), _) => Token::Uint(unsigned(value, *bits).ok_or_else(mismatch)?),
+        (ParamType::Int(bits), _) => Token::Int(signed(value, *bits).ok_or_else(mismatch)?),
+        (ParamType::Bool, Value::Bool(flag)) => Token::Bool(*flag),
+        (ParamType::String, Value::String(string)) => Token::String(string.clone()),
+        (ParamType::Bytes, Value::String(_)) => Token::Bytes(bytes(value).ok_or_else(mismatch)?),
+        (ParamType::FixedBytes(len), Value::String(_)) => {
+            let bytes = bytes(value).filter(|bytes| bytes.len() == *len).ok_or_else(mismatch)?;
+            Token::FixedBytes(bytes)
+        },
+        (ParamType::Array(inner), Value::Array(items)) => {
+            Token::Array(items.iter().map(|item| token(inner, item)).collect::<Result<_, _>>()?)
+        },
+        (ParamType::FixedArray(inner, len), Value::Array(items)) if items.len() == *len => {
+            Token::FixedArray(
+                items.iter().map(|item| token(inner, item)).collect::<Result<_, _>>()?,
+            )
+        },
+        (ParamType::Tuple(kinds), Value::Array(items)) if items.len() == kinds.len() => {
+            Token::Tuple(
+                kinds
+                    .iter()
+                    .zip(items)
+                    .map(|(kind, item)| token(kind, item))
+                    .collect::<Result<_, _>>()?,
+            )
+        },
+        _ => return Err(mismatch()),
+    };
+
+    Ok(token)
+}
+
+fn unsigned(value: &Value, bits: usize) -> Option<U256> {
+    let value = match value {
+        Value::Number(number) => number.as_u64().map(U256::from)?,
+        // `from_dec_str` takes an empty string for zero.
+        Value::String(string) if !string.is_empty() => U256::from_dec_str(string).ok()?,
+        _ => return None,
+    };
+    if bits < 256 && value >> bits != U256::zero() {
+        return None;
+    }
+
+    Some(value)
+}
+
+/// Parses a signed integer of `bits` bits into its 256 bit two's complement representation.
+fn signed(value: &Value, bits: usize) -> Option<U256> {
+    let string = match value {
+        Value::Number(number) => number.as_i64()?.to_string(),
+        Value::String(string) => string.clone(),
+        _ => return None,
+    };
+
+    let (negative, digits) = match string.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, string.as_str()),
+    };
+    if digits.is_empty() || bits == 0 || bits > 256 {
+        return None;
+    }
+    let magnitude = U256::from_dec_str(digits).ok()?;
+    let max = U256::MAX >> (257 - bits);
+    if negative && magnitude <= max + 1 {
+        return Some((!magnitude).overflowing_add(U256::one()).0);
+    } else if negative || magnitude > max {
+        return None;
+    }
+
+    Some(magnitude)
+}
+
+fn bytes(value: &Value) -> Option<Vec<u8>> {
+    serde_json::from_value::<Bytes>(value.clone()).ok().map(|bytes| bytes.0)
+}
+
+/// Decodes what `function` returned into one value per output, in order, named after the output
+/// or, for unnamed outputs, its position.
+pub fn decode(function: &Function, data: &[u8]) -> anyhow::Result<Vec<(String, Value)>> {
+    let tokens = function.decode_output(data).context("Failed to decode return values")?;
+
+    Ok(function
+        .outputs
+        .iter()
+        .zip(tokens)
+        .enumerate()
+        .map(|(index, (param, token))| {
+            let name = if param.name.is_empty() { index.to_string() } else { param.name.clone() };
+            (name, token_to_value(token))
+        })
+        .collect())
+}
+
+/// Addresses and bytes become `0x` prefixed hex, integers numbers or, when they don't fit, decimal
+/// strings, and arrays and tuples JSON arrays.
+fn token_to_value(token: Token) -> Value {
+    match token {
+        Token::Address(address) => Value::String(format!("{:?}", address)),
+        Token::Bytes(bytes) | Token::FixedBytes(bytes) => {
+            Value::String(format!("{:?}", Bytes(bytes)))
+        },
+        Token::Uint(value) => chain::u256_to_value(value),
+        Token::Int(value) if value.bit(255) => {
+            let magnitude = (!value).overflowing_add(U256::one()).0;
+            if magnitude <= U256::from(i64::MAX) {
+                return Value::from(-(magnitude.as_u64() as i64));
+            }
+            Value::String(format!("-{}", magnitude))
+        },
+        Token::Int(value) => chain::u256_to_value(value),
+        Token::Bool(flag) => Value::Bool(flag),
+        Token::String(string) => Value::String(string),
+        Token::Array(tokens) | Token::FixedArray(tokens) | Token::Tuple(tokens) => {
+            Value::Array(tokens.into_iter().map(token_to_value).collect())
+        },
+    }
+}
+
+/// Sends `data`, an encoded call of `function`, to `address` with `value` wei attached. Receipts
+/// don't carry return values, so the ones published come from simulating the call right before
+/// it is sent. `tx_hash`, `gas_used` and `status` win over return values of the same name.
+pub async fn call(
+    address: Address, function: &Function, data: Vec<u8>, value: U256, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let parameters = chain::transaction(&web3s, address, value, data).await?;
+    let returned = chain::simulate(&web3s, chain::account()?, &parameters).await?;
+    let returns = decode(function, &returned.0)?;
+
+    let submission = if dry_run {
+        Submission::unsent(parameters)
+    } else {
+        chain::send(&web3s, parameters).await?
+    };
+
+    let mut outcome = ActionOutcome::default();
+    outcome.outputs.extend(returns);
+    outcome.outputs.extend(chain::submission_outputs(&submission));
+    outcome.submissions.push(submission);
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `2^255`, the magnitude of the smallest `int256`.
+    fn int256_min_magnitude() -> U256 { U256::one() << 255 }
+
+    #[test]
+    fn signed_encodes_twos_complement() {
+        assert_eq!(signed(&Value::from(5), 256), Some(U256::from(5)));
+        assert_eq!(signed(&Value::from(-1), 256), Some(U256::MAX));
+        assert_eq!(signed(&Value::from("-2"), 256), Some(U256::MAX - 1));
+        assert_eq!(signed(&Value::from("0"), 256), Some(U256::zero()));
+    }
+
+    #[test]
+    fn signed_accepts_the_int256_bounds() {
+        let min = format!("-{}", int256_min_magnitude());
+        assert_eq!(signed(&Value::String(min), 256), Some(int256_min_magnitude()));
+
+        let max = (int256_min_magnitude() - 1).to_string();
+        assert_eq!(signed(&Value::String(max), 256), Some(int256_min_magnitude() - 1));
+    }
+
+    #[test]
+    fn signed_rejects_out_of_range_values() {
+        let too_small = format!("-{}", int256_min_magnitude() + 1);
+        assert_eq!(signed(&Value::String(too_small), 256), None);
+        assert_eq!(signed(&Value::String(int256_min_magnitude().to_string()), 256), None);
+        assert_eq!(signed(&Value::String(U256::MAX.to_string()), 256), None);
+        assert_eq!(signed(&Value::from("1".repeat(80)), 256), None);
+        assert_eq!(signed(&Value::from("-"), 256), None);
+        assert_eq!(signed(&Value::from(""), 256), None);
+        assert_eq!(signed(&Value::Bool(true), 256), None);
+    }
+
+    #[test]
+    fn signed_checks_the_bit_width() {
+        assert_eq!(signed(&Value::from(127), 8), Some(U256::from(127)));
+        assert_eq!(signed(&Value::from(-128), 8), Some(U256::MAX - 127));
+        assert_eq!(signed(&Value::from(128), 8), None);
+        assert_eq!(signed(&Value::from(-129), 8), None);
+        assert_eq!(signed(&Value::from(i64::MIN), 64), Some(U256::MAX - i64::MAX));
+    }
+
+    #[test]
+    fn unsigned_parses_numbers_and_decimal_strings() {
+        assert_eq!(unsigned(&Value::from(7), 256), Some(U256::from(7)));
+        assert_eq!(unsigned(&Value::from("0"), 256), Some(U256::zero()));
+        assert_eq!(unsigned(&Value::String(U256::MAX.to_string()), 256), Some(U256::MAX));
+        assert_eq!(unsigned(&Value::from(""), 256), None);
+        assert_eq!(unsigned(&Value::from("-1"), 256), None);
+        assert_eq!(unsigned(&Value::from(-1), 256), None);
+        assert_eq!(unsigned(&Value::Bool(true), 256), None);
+    }
+
+    #[test]
+    fn unsigned_checks_the_bit_width() {
+        assert_eq!(unsigned(&Value::from(255), 8), Some(U256::from(255)));
+        assert_eq!(unsigned(&Value::from(256), 8), None);
+        assert_eq!(unsigned(&Value::String((U256::one() << 160).to_string()), 160), None);
+        assert!(token(&ParamType::Uint(24), &Value::from(1 << 24)).is_err());
+    }
+
+    #[test]
+    fn token_to_value_decodes_negative_ints() {
+        assert_eq!(token_to_value(Token::Int(U256::MAX)), Value::from(-1));
+        assert_eq!(token_to_value(Token::Int(U256::MAX - i64::MAX + 1)), Value::from(-i64::MAX));
+        assert_eq!(
+            token_to_value(Token::Int(int256_min_magnitude())),
+            Value::String(format!("-{}", int256_min_magnitude())),
+        );
+    }
+
+    #[test]
+    fn token_to_value_round_trips_signed() {
+        let values = ["-1", "42", "-9223372036854775809", "-5789604461865809771178549250434395392"];
+        for value in &values {
+            let token = Token::Int(signed(&Value::from(*value), 256).unwrap());
+            let decoded = match token_to_value(token) {
+                Value::String(string) => string,
+                number => number.to_string(),
+            };
+            assert_eq!(&decoded, value);
+        }
+    }
+
+    #[test]
+    fn token_to_value_keeps_large_uints_exact() {
+        assert_eq!(token_to_value(Token::Uint(U256::from(7))), Value::from(7));
+        assert_eq!(token_to_value(Token::Uint(U256::MAX)), Value::String(U256::MAX.to_string()));
+    }
+}
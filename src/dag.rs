@@ -1,91 +1,279 @@
 use daggy::Walker;
-use num::Zero;
-use secp256k1::SecretKey;
+use futures::future::BoxFuture;
+use num::traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedRem, CheckedSub, Zero};
 use serde::*;
 use serde_json::{Number, Value};
 use std::str::FromStr;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{
-    collections::HashMap,
-    env,
-    ops::{Add, Div, Mul, Rem, Sub},
-};
-use web3::contract::tokens::Tokenize;
-use web3::contract::Options;
-use web3::types::{Bytes, TransactionParameters, U256};
-use web3::{
-    contract::Contract,
-    types::{Address, H160},
+use std::{collections::HashMap, fmt::Display};
+use web3::ethabi::{self, Function, ParamType, Token};
+use web3::types::{Address, U256};
+
+use crate::actions::{self, ActionContext};
+use crate::contract::{self, AbiSource};
+use crate::execution::{
+    self, ActionOutcome, ErrorKind, EvaluatedCondition, ExecutionError, RunReport, SkippedEdge,
+    Step,
 };
+use crate::multicall::{self, Read};
+use crate::{aave, chain, price_feed, token, validation};
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde()]
-enum ZapType {
+pub enum ZapType {
     #[serde(rename = "ARITHMETIC")]
     Arithmetic,
     #[serde(rename = "ROOT")]
     Root,
     #[serde(rename = "ACTION")]
     Action,
+    #[serde(rename = "QUERY")]
+    Query,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+/// What a `QUERY` node reads. Queries only read chain state and never send a transaction.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[serde()]
-enum ActionType {
-    #[serde(rename = "SWAP_EXACT_ETH_FOR_TOKENS")]
-    SwapExactETHForTokens,
+pub enum QueryType {
+    /// Calls a view function, like `balanceOf` or `getReserves`, through `eth_call`.
+    #[serde(rename = "CALL")]
+    Call,
+    /// Reads the native balance of `account`.
+    #[serde(rename = "BALANCE")]
+    Balance,
+    /// Reads the latest price of a Chainlink-style aggregator at `feed_address`.
+    #[serde(rename = "PRICE_FEED")]
+    PriceFeed,
+    /// Reads the collateral, debt and health factor of `account` on an Aave pool.
+    #[serde(rename = "AAVE_ACCOUNT_DATA")]
+    AaveAccountData,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Declares `ActionType` from one table of variants and the `action_type` each is saved and
+/// registered under, so the serialized names, `ALL` and `as_str` can't drift apart.
+macro_rules! action_types {
+    ($($(#[doc = $doc:literal])* $variant:ident => $name:literal,)*) => {
+        #[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+        pub enum ActionType {
+            $($(#[doc = $doc])* #[serde(rename = $name)] $variant,)*
+        }
+
+        impl ActionType {
+            /// Every built-in action, each registered under `as_str` as its `action_type`.
+            pub const ALL: &'static [ActionType] = &[$(ActionType::$variant,)*];
+
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(ActionType::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+action_types! {
+    SwapExactETHForTokens => "SWAP_EXACT_ETH_FOR_TOKENS",
+    SwapExactTokensForTokens => "SWAP_EXACT_TOKENS_FOR_TOKENS",
+    SwapExactTokensForETH => "SWAP_EXACT_TOKENS_FOR_ETH",
+    SwapTokensForExactTokens => "SWAP_TOKENS_FOR_EXACT_TOKENS",
+    SwapETHForExactTokens => "SWAP_ETH_FOR_EXACT_TOKENS",
+    SwapTokensForExactETH => "SWAP_TOKENS_FOR_EXACT_ETH",
+    SwapExactTokensForTokensSupportingFeeOnTransferTokens =>
+        "SWAP_EXACT_TOKENS_FOR_TOKENS_SUPPORTING_FEE_ON_TRANSFER_TOKENS",
+    SwapExactETHForTokensSupportingFeeOnTransferTokens =>
+        "SWAP_EXACT_ETH_FOR_TOKENS_SUPPORTING_FEE_ON_TRANSFER_TOKENS",
+    SwapExactTokensForETHSupportingFeeOnTransferTokens =>
+        "SWAP_EXACT_TOKENS_FOR_ETH_SUPPORTING_FEE_ON_TRANSFER_TOKENS",
+    Approve => "APPROVE",
+    AddLiquidity => "ADD_LIQUIDITY",
+    AddLiquidityETH => "ADD_LIQUIDITY_ETH",
+    RemoveLiquidity => "REMOVE_LIQUIDITY",
+    RemoveLiquidityETH => "REMOVE_LIQUIDITY_ETH",
+    TransferETH => "TRANSFER_ETH",
+    TransferERC20 => "TRANSFER_ERC20",
+    WrapETH => "WRAP_ETH",
+    UnwrapWETH => "UNWRAP_WETH",
+    V3ExactInputSingle => "V3_EXACT_INPUT_SINGLE",
+    V3ExactInput => "V3_EXACT_INPUT",
+    V3ExactOutputSingle => "V3_EXACT_OUTPUT_SINGLE",
+    ContractCall => "CONTRACT_CALL",
+    AaveSupply => "AAVE_SUPPLY",
+    /// The name older Aave pools give `supply`.
+    AaveDeposit => "AAVE_DEPOSIT",
+    AaveWithdraw => "AAVE_WITHDRAW",
+    AaveBorrow => "AAVE_BORROW",
+    AaveRepay => "AAVE_REPAY",
+}
+
+impl ActionType {
+    pub fn is_swap(self) -> bool {
+        matches!(
+            self,
+            ActionType::SwapExactETHForTokens
+                | ActionType::SwapExactTokensForTokens
+                | ActionType::SwapExactTokensForETH
+                | ActionType::SwapTokensForExactTokens
+                | ActionType::SwapETHForExactTokens
+                | ActionType::SwapTokensForExactETH
+                | ActionType::SwapExactTokensForTokensSupportingFeeOnTransferTokens
+                | ActionType::SwapExactETHForTokensSupportingFeeOnTransferTokens
+                | ActionType::SwapExactTokensForETHSupportingFeeOnTransferTokens
+        )
+    }
+
+    /// Swaps that fix the output amount and bound the input, rather than the other way around.
+    pub fn is_exact_output(self) -> bool {
+        matches!(
+            self,
+            ActionType::SwapTokensForExactTokens
+                | ActionType::SwapETHForExactTokens
+                | ActionType::SwapTokensForExactETH
+                | ActionType::V3ExactOutputSingle
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde()]
-struct NodeData {
+pub struct NodeData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) left: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) right: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) operator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_from_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_to_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_from_amount: Option<String>,
+    /// The exact amount an exact-output swap buys.
     #[serde(skip_serializing_if = "Option::is_none")]
-    left: Option<String>,
+    pub(crate) token_to_amount: Option<String>,
+    /// The least an exact-input swap may be quoted for.
     #[serde(skip_serializing_if = "Option::is_none")]
-    right: Option<String>,
+    pub(crate) amount_out_min: Option<String>,
+    /// The most an exact-output swap may be quoted to spend.
     #[serde(skip_serializing_if = "Option::is_none")]
-    operator: Option<String>,
+    pub(crate) amount_in_max: Option<String>,
+    /// How far, in basis points, a swap may move from its quote. Defaults to `SLIPPAGE_BPS`.
     #[serde(skip_serializing_if = "Option::is_none")]
-    result: Option<String>,
+    pub(crate) slippage_bps: Option<String>,
+    /// Every token a swap goes through, replacing `token_from_address` and `token_to_address`.
     #[serde(skip_serializing_if = "Option::is_none")]
-    token_from_address: Option<String>,
+    pub(crate) path: Option<Vec<String>>,
+    /// Lets the router pick the path between the ends of the swap.
     #[serde(skip_serializing_if = "Option::is_none")]
-    token_to_address: Option<String>,
+    pub(crate) auto_route: Option<bool>,
+    /// The pool fee tier of a single-pool V3 swap, in hundredths of a basis point.
     #[serde(skip_serializing_if = "Option::is_none")]
-    token_from_amount: Option<String>,
+    pub(crate) fee: Option<String>,
+    /// The fee tier of every hop of a multi-hop V3 `path`.
     #[serde(skip_serializing_if = "Option::is_none")]
-    action_type: Option<ActionType>,
+    pub(crate) fees: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_address: Option<String>,
+    /// Who an approval is for, the router when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) spender: Option<String>,
+    /// Who a transfer pays, which must be on the `TRANSFER_ALLOWLIST`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) recipient: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_a_address: Option<String>,
+    /// The other side of a pool, left out by the ETH liquidity actions where it is ETH.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) token_b_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount_a_desired: Option<String>,
+    /// The ETH sent along by `ADD_LIQUIDITY_ETH`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount_b_desired: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount_a_min: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount_b_min: Option<String>,
+    /// The LP tokens a removal burns.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) liquidity: Option<String>,
+    /// The contract a `CONTRACT_CALL` calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) contract_address: Option<String>,
+    /// The contract's ABI, inline instead of from the registry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) abi: Option<Value>,
+    /// The name of the contract's ABI in the registry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) abi_name: Option<String>,
+    /// The function to call, by name or, when overloaded, by full signature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) function: Option<String>,
+    /// One value per parameter of `function`. Strings starting with `$` are looked up in the run's
+    /// variables, also inside arrays.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) args: Option<Vec<Value>>,
+    /// The wei sent along with a `CONTRACT_CALL`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<String>,
+    /// Whose balance a `BALANCE` query, or account data an `AAVE_ACCOUNT_DATA` query, reads. The
+    /// signing account when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) account: Option<String>,
+    /// The Aave pool a lending action or `AAVE_ACCOUNT_DATA` query uses, `AAVE_POOL_ADDRESS`
+    /// when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pool_address: Option<String>,
+    /// Whether an Aave borrow, or the debt a repayment pays off, is at the `variable` rate, the
+    /// default, or the `stable` one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) interest_rate_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) query_type: Option<QueryType>,
+    /// The aggregator a `PRICE_FEED` query reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) feed_address: Option<String>,
+    /// How old, in seconds, a price feed's round may be. Defaults to `PRICE_FEED_MAX_AGE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_age: Option<String>,
+    /// The key of the action's handler in the `actions` registry, like `SWAP_EXACT_ETH_FOR_TOKENS`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) action_type: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde()]
 pub struct Node {
-    id: u32,
+    pub(crate) id: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
-    children: Option<Vec<Edge>>,
-    zap_type: ZapType,
+    pub(crate) children: Option<Vec<Edge>>,
+    pub(crate) zap_type: ZapType,
     #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<NodeData>,
+    pub(crate) data: Option<NodeData>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde()]
 pub struct Edge {
-    id: u32,
+    pub(crate) id: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
-    condition: Option<Condition>,
+    pub(crate) condition: Option<Condition>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde()]
 pub struct Condition {
-    right: String,
-    left: String,
-    operator: String,
+    pub(crate) right: String,
+    pub(crate) left: String,
+    pub(crate) operator: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DagNode {
+    id: u32,
     zap_type: ZapType,
     #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<NodeData>,
@@ -97,236 +285,672 @@ pub struct DagEdge {
     condition: Option<Condition>,
 }
 
-pub fn parse(dag_data: Vec<Node>) -> (daggy::Dag<DagNode, DagEdge>, Option<daggy::NodeIndex<u32>>) {
+pub fn parse(
+    dag_data: Vec<Node>,
+) -> Result<(daggy::Dag<DagNode, DagEdge>, daggy::NodeIndex<u32>), Vec<validation::Problem>> {
+    let problems = validation::validate(&dag_data);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
     let mut dag = daggy::Dag::<DagNode, DagEdge, u32>::new();
 
-    let mut nodes = Vec::new();
     let mut nodes_map = HashMap::new();
-    let mut root_node_index: Option<daggy::NodeIndex<u32>> = None;
+    let mut root_node_index = daggy::NodeIndex::end();
     for node in &dag_data {
-        let node_id =
-            dag.add_node(DagNode { zap_type: node.zap_type.clone(), data: node.data.clone() });
-        nodes.push(node_id);
+        let node_id = dag.add_node(DagNode {
+            id: node.id,
+            zap_type: node.zap_type.clone(),
+            data: node.data.clone(),
+        });
         nodes_map.insert(node.id, node_id);
 
         if ZapType::Root == node.zap_type {
-            root_node_index = Some(node_id);
+            root_node_index = node_id;
         }
     }
 
     for node in &dag_data {
-        if let Some(children) = &node.children {
-            for child in children {
-                let parent_index = nodes_map.get(&node.id).unwrap();
-                let child_index = nodes_map.get(&child.id).unwrap();
-
-                let result = dag.add_edge(
-                    *parent_index,
-                    *child_index,
-                    DagEdge { condition: child.condition.clone() },
-                );
-                if let Err(e) = result {
-                    panic!("Failed to add edge: {:?}", e);
-                }
-            }
+        for child in node.children.iter().flatten() {
+            // The validator has already rejected dangling ids and cycles.
+            let _ = dag.add_edge(
+                nodes_map[&node.id],
+                nodes_map[&child.id],
+                DagEdge { condition: child.condition.clone() },
+            );
         }
     }
 
-    return (dag, root_node_index);
+    return Ok((dag, root_node_index));
 }
 
-pub fn walk(
-    dag: daggy::Dag<DagNode, DagEdge>,
+/// Visits every child of `root_node_index` whose edge condition holds, depth first, awaiting each
+/// action before moving on so later nodes can use its outputs. A node several paths lead to runs
+/// once, with the variables of the first path that reaches it.
+pub fn walk<'a>(
+    dag: &'a daggy::Dag<DagNode, DagEdge>,
     root_node_index: daggy::NodeIndex<u32>,
     vars: serde_json::Map<String, serde_json::Value>,
-) {
-    dag.children(root_node_index).iter(&dag).for_each(|child| {
-        let child_node_index = child.1;
-        let child_node = dag.node_weight(child_node_index).unwrap();
-        let edge_index = child.0;
-        let dag = dag.clone();
-
-        if let Some(edge) = dag.edge_weight(edge_index) {
-            if let Some(condition) = &edge.condition {
-                let tmp_left: Value;
-                let mut left_value: Option<&Value> = None;
-                if condition.left.starts_with("$") {
-                    left_value = vars.get(&condition.left);
-                } else if condition.left.len() > 0 {
-                    tmp_left = normalize_value(condition.left.clone());
-                    left_value = Some(&tmp_left);
-                }
+    report: &'a mut RunReport,
+) -> BoxFuture<'a, Result<(), ExecutionError>> {
+    // Recursive async functions need their future boxed.
+    Box::pin(visit(dag, root_node_index, vars, report))
+}
 
-                let tmp_right: Value;
-                let mut right_value: Option<&Value> = None;
-                if condition.right.starts_with("$") {
-                    right_value = vars.get(&condition.right);
-                } else if condition.right.len() > 0 {
-                    tmp_right = normalize_value(condition.right.clone());
-                    right_value = Some(&tmp_right);
-                }
+async fn visit(
+    dag: &daggy::Dag<DagNode, DagEdge>,
+    root_node_index: daggy::NodeIndex<u32>,
+    vars: serde_json::Map<String, serde_json::Value>,
+    report: &mut RunReport,
+) -> Result<(), ExecutionError> {
+    let parent_id = dag[root_node_index].id;
+    if report.steps.is_empty() {
+        report.steps.push(Step { node_id: parent_id, vars: vars.clone() });
+    }
 
-                if left_value.is_none() || right_value.is_none() {
-                    panic!(
-                        "Missing variable, left: {:?}, right: {:?}, child: {:?}",
-                        left_value, right_value, child_node_index
-                    );
-                } else if let (Some(left), Some(right)) = (left_value, right_value) {
-                    if !evaluate_condition(left, right, &condition.operator) {
-                        return;
-                    }
-                }
+    let mut prefetched = prefetch_queries(dag, root_node_index, &vars).await;
+    let transactions_at_prefetch = report.transactions.len();
+
+    for (edge_index, child_node_index) in dag.children(root_node_index).iter(dag) {
+        let child_node = &dag[child_node_index];
+        // Every visited node has a step, so a join reached before isn't run a second time.
+        if report.steps.iter().any(|step| step.node_id == child_node.id) {
+            log::debug!("node {} was already visited from another parent", child_node.id);
+            continue;
+        }
+
+        if let Some(condition) = &dag[edge_index].condition {
+            let left = resolve_operand(&condition.left, &vars, child_node.id)?;
+            let right = resolve_operand(&condition.right, &vars, child_node.id)?;
+            let passed = evaluate_condition(&left, &right, &condition.operator, child_node.id)?;
+            report.conditions.push(EvaluatedCondition {
+                from: parent_id,
+                to: child_node.id,
+                left,
+                operator: condition.operator.clone(),
+                right,
+                passed,
+            });
+            if !passed {
+                report.skipped_edges.push(SkippedEdge {
+                    from: parent_id,
+                    to: child_node.id,
+                    condition: condition.clone(),
+                });
+                continue;
             }
         }
 
         let mut new_vars = vars.clone();
 
-        let mut done = false;
         match child_node.zap_type {
             ZapType::Arithmetic => {
-                if let Some(data) = &child_node.data {
-                    if data.left.is_none()
-                        || data.right.is_none()
-                        || data.operator.is_none()
-                        || data.result.is_none()
-                    {
-                        panic!(
-                            "Missing data, left: {:?}, right: {:?}, operator: {:?}, result: {:?}",
-                            data.left, data.right, data.operator, data.result
-                        );
-                    }
-
-                    let tmp_left: Value;
-                    let mut left_value: Option<&Value> = None;
-                    if data.left.clone().unwrap().starts_with("$") {
-                        left_value = vars.get(&data.left.clone().unwrap());
-                    } else if data.left.clone().unwrap().len() > 0 {
-                        tmp_left = normalize_value(data.left.clone().unwrap());
-                        left_value = Some(&tmp_left);
-                    }
-
-                    let tmp_right: Value;
-                    let mut right_value: Option<&Value> = None;
-                    if data.right.clone().unwrap().starts_with("$") {
-                        right_value = vars.get(&data.right.clone().unwrap());
-                    } else if data.right.clone().unwrap().len() > 0 {
-                        tmp_right = normalize_value(data.right.clone().unwrap());
-                        right_value = Some(&tmp_right);
-                    }
-
-                    if left_value.is_none() || right_value.is_none() {
-                        panic!(
-                            "Missing variable, left: {:?}, right: {:?}, child: {:?}",
-                            left_value, right_value, child_node_index
-                        );
-                    }
-
-                    let result_value = evaluate_arithmetic(
-                        &left_value.unwrap(),
-                        &right_value.unwrap(),
-                        &data.operator.clone().unwrap(),
-                    );
-
-                    println!(
-                        "{} = {} {} {}",
-                        data.result.clone().unwrap(),
-                        left_value.unwrap(),
-                        data.operator.clone().unwrap(),
-                        right_value.unwrap()
-                    );
-                    println!("Result: {:?}", result_value);
-                    new_vars.insert(data.result.clone().unwrap(), result_value);
-
-                    done = true;
-                }
+                let data = required_data(child_node)?;
+                let (left, right, operator, result) =
+                    match (&data.left, &data.right, &data.operator, &data.result) {
+                        (Some(left), Some(right), Some(operator), Some(result)) => {
+                            (left, right, operator, result)
+                        },
+                        _ => {
+                            return Err(ExecutionError::new(
+                                child_node.id,
+                                ErrorKind::MissingNodeData,
+                                format!(
+                                    "left: {:?}, right: {:?}, operator: {:?}, result: {:?}",
+                                    data.left, data.right, data.operator, data.result
+                                ),
+                            ))
+                        },
+                    };
+
+                let left_value = resolve_operand(left, &vars, child_node.id)?;
+                let right_value = resolve_operand(right, &vars, child_node.id)?;
+                let result_value =
+                    evaluate_arithmetic(&left_value, &right_value, operator, child_node.id)?;
+
+                log::debug!(
+                    "{} = {} {} {} = {}",
+                    result,
+                    left_value,
+                    operator,
+                    right_value,
+                    result_value
+                );
+                new_vars.insert(result.clone(), result_value);
             },
-            ZapType::Root => {
-                done = true;
+            ZapType::Root => {},
+            ZapType::Query => {
+                let data = required_data(child_node)?;
+                let (plan, reads) = plan_query(data, &vars, child_node.id)?;
+                let results = match prefetched.remove(&child_node_index) {
+                    // A transaction sent since the prefetch may have changed what it read.
+                    Some(results) if report.transactions.len() == transactions_at_prefetch => {
+                        results
+                    },
+                    _ => multicall::read_all(&reads)
+                        .await
+                        .map_err(|e| query_failed(child_node.id, &e))?,
+                };
+                let results = query_results(plan, results, child_node.id)?;
+
+                if let Some(result) = data.result.as_deref().filter(|result| !result.is_empty()) {
+                    let (_, value) = results.first().ok_or_else(|| {
+                        ExecutionError::new(
+                            child_node.id,
+                            ErrorKind::QueryFailed,
+                            format!("nothing was returned to store in {}", result),
+                        )
+                    })?;
+                    new_vars.insert(result.to_string(), value.clone());
+                }
+                for (name, value) in results {
+                    new_vars.insert(format!("${}.{}", child_node.id, name), value);
+                }
             },
             ZapType::Action => {
-                if let Some(data) = &child_node.data {
-                    if data.action_type.is_none()
-                        || data.token_from_address.is_none()
-                        || data.token_to_address.is_none()
-                        || data.token_from_amount.is_none()
-                    {
-                        panic!("Missing data, action: {:?}", data);
-                    }
-
-                    match data.action_type.clone().unwrap() {
-                        ActionType::SwapExactETHForTokens => {
-                            let token_from_amount = data.token_from_amount.clone().unwrap();
-                            let token_from_amount_value;
-                            let vtmp = vars.get(&token_from_amount.clone());
-                            if token_from_amount.clone().starts_with("$") && !vtmp.is_none() {
-                                if vtmp.unwrap().is_u64() {
-                                    token_from_amount_value = vtmp.unwrap().as_u64().unwrap();
-                                } else {
-                                    panic!("Invalid token_from_amount var: {:?}", vtmp);
-                                }
-                            } else if token_from_amount.clone().len() > 0 {
-                                let tmp = normalize_value(token_from_amount.clone());
-                                if tmp.is_i64() {
-                                    token_from_amount_value = tmp.as_u64().unwrap();
-                                } else {
-                                    panic!("Invalid token_from_amount: {:?}", tmp);
-                                }
-                            } else {
-                                panic!("Invalid token_from_amount: {:?}", token_from_amount);
-                            }
-
-                            let token_from_address = data.token_from_address.clone().unwrap();
-                            let token_to_address = data.token_to_address.clone().unwrap();
-
-                            tokio::spawn(async move {
-                                swap_exact_eth_for_tokens(
-                                    token_from_address,
-                                    token_to_address,
-                                    token_from_amount_value,
-                                )
-                                .await;
-                            });
-                        },
-                    }
-                    done = true;
-                }
+                let data = required_data(child_node)?;
+                let action_type = required_field(&data.action_type, "action_type", child_node.id)?;
+                let handler = actions::handler(action_type).ok_or_else(|| {
+                    ExecutionError::new(
+                        child_node.id,
+                        ErrorKind::UnknownActionType,
+                        format!("no action is registered as {}", action_type),
+                    )
+                })?;
+                let data = &base_unit_amounts(data, child_node.id).await?;
+                let context = ActionContext {
+                    node_id: child_node.id,
+                    data,
+                    vars: &vars,
+                    dry_run: report.dry_run,
+                };
+                execution::checkpoint(report, child_node.id).await;
+                let outcome = handler.run(&context).await?;
+
+                publish(report, &mut new_vars, child_node.id, outcome)?;
             },
         }
 
-        println!("Child: {:?}, parent: {:?}", child_node_index, root_node_index);
+        report.steps.push(Step { node_id: child_node.id, vars: new_vars.clone() });
+        execution::checkpoint(report, child_node.id).await;
+        walk(dag, child_node_index, new_vars, report).await?;
+    }
+
+    Ok(())
+}
 
-        if done {
-            walk(dag, child_node_index, new_vars);
-        } else {
-            panic!("Faild in node: {:?}", child_node_index);
+/// Records the transactions of an action and exposes its outputs as `$<node id>.<name>`. An action
+/// that failed midway, or a reverted or unconfirmed transaction, stops the run once they have been
+/// recorded.
+fn publish(
+    report: &mut RunReport, vars: &mut serde_json::Map<String, serde_json::Value>, node_id: u32,
+    outcome: ActionOutcome,
+) -> Result<(), ExecutionError> {
+    for submission in &outcome.submissions {
+        report.record(node_id, submission);
+    }
+
+    for (name, value) in outcome.outputs {
+        vars.insert(format!("${}.{}", node_id, name), value);
+    }
+
+    if let Some(failure) = &outcome.failure {
+        return Err(transaction_failed(node_id, failure));
+    }
+
+    if let Some(reverted) = outcome.submissions.iter().find(|submission| submission.reverted()) {
+        let hash = reverted.receipt.as_ref().map(|receipt| receipt.transaction_hash);
+        return Err(ExecutionError::new(
+            node_id,
+            ErrorKind::TransactionReverted,
+            format!(
+                "transaction {:?} reverted: {}",
+                hash.unwrap_or_default(),
+                reverted.revert_reason.as_deref().unwrap_or("unknown reason")
+            ),
+        ));
+    }
+
+    let unconfirmed = outcome.submissions.iter().find(|submission| submission.unconfirmed());
+    if let Some(hash) = unconfirmed.and_then(|submission| submission.hash) {
+        return Err(ExecutionError::new(
+            node_id,
+            ErrorKind::TransactionFailed,
+            format!("transaction {:?} was sent but never confirmed", hash),
+        ));
+    }
+
+    Ok(())
+}
+
+fn required_data(node: &DagNode) -> Result<&NodeData, ExecutionError> {
+    node.data.as_ref().ok_or_else(|| {
+        ExecutionError::new(node.id, ErrorKind::MissingNodeData, "node has no data")
+    })
+}
+
+pub(crate) fn required_field<'a>(
+    field: &'a Option<String>, name: &str, node_id: u32,
+) -> Result<&'a str, ExecutionError> {
+    field.as_deref().filter(|value| !value.is_empty()).ok_or_else(|| {
+        ExecutionError::new(node_id, ErrorKind::MissingNodeData, format!("{} is not set", name))
+    })
+}
+
+/// Rewrites the amounts of an action that are written with a unit, like `"1.5 ether"` or
+/// `"250 USDC"`, into base units of the token each amount is in. Swap amounts are in the token at
+/// their end of the path, liquidity amounts in their side of the pool, `amount` in
+/// `token_address`, and amounts without a token can only use `wei`, `gwei` and `ether`.
+async fn base_unit_amounts(data: &NodeData, node_id: u32) -> Result<NodeData, ExecutionError> {
+    let mut data = data.clone();
+    let path = data.path.clone().filter(|path| !path.is_empty());
+    let token_in =
+        path.as_ref().and_then(|path| path.first().cloned()).or(data.token_from_address.clone());
+    let token_out = path.and_then(|path| path.last().cloned()).or(data.token_to_address.clone());
+    let token = data.token_address.clone();
+    let token_a = data.token_a_address.clone();
+    let token_b = data.token_b_address.clone();
+
+    let amounts = vec![
+        (&mut data.token_from_amount, token_in.clone()),
+        (&mut data.amount_in_max, token_in),
+        (&mut data.token_to_amount, token_out.clone()),
+        (&mut data.amount_out_min, token_out),
+        (&mut data.amount, token),
+        (&mut data.amount_a_desired, token_a.clone()),
+        (&mut data.amount_a_min, token_a),
+        (&mut data.amount_b_desired, token_b.clone()),
+        (&mut data.amount_b_min, token_b),
+        (&mut data.liquidity, None),
+        (&mut data.value, None),
+    ];
+    for (amount, token) in amounts {
+        let with_unit = match amount.as_deref() {
+            Some(with_unit) if token::has_unit(with_unit) => with_unit,
+            _ => continue,
+        };
+        let token = match token.as_deref() {
+            Some(token) => Some(parse_address(token, node_id)?),
+            None => None,
+        };
+
+        let base_units = token::to_base_units(with_unit, token).await.map_err(|e| {
+            ExecutionError::new(node_id, ErrorKind::InvalidAmount, format!("{:#}", e))
+        })?;
+        *amount = Some(base_units.to_string());
+    }
+
+    Ok(data)
+}
+
+/// Looks `$name` operands up in `vars` and parses anything else as a literal.
+fn resolve_operand(
+    operand: &str, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<Value, ExecutionError> {
+    if operand.starts_with('$') {
+        return vars.get(operand).cloned().ok_or_else(|| {
+            ExecutionError::new(
+                node_id,
+                ErrorKind::MissingVariable,
+                format!("variable {} is not set", operand),
+            )
+        });
+    } else if operand.is_empty() {
+        return Err(ExecutionError::new(node_id, ErrorKind::MissingVariable, "empty operand"));
+    }
+
+    Ok(normalize_value(operand.to_string()))
+}
+
+/// Amounts are in the token's smallest unit and easily exceed `u64`, so decimal strings are
+/// accepted as well as JSON numbers.
+pub(crate) fn resolve_amount(
+    amount: &str, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<U256, ExecutionError> {
+    if let Some(literal) = unsigned(&Value::from(amount)) {
+        return Ok(literal);
+    }
+
+    let value = resolve_operand(amount, vars, node_id)?;
+    unsigned(&value).ok_or_else(|| {
+        ExecutionError::new(
+            node_id,
+            ErrorKind::InvalidAmount,
+            format!("{} resolved to {}, expected an unsigned integer", amount, value),
+        )
+    })
+}
+
+pub(crate) fn parse_address(address: &str, node_id: u32) -> Result<Address, ExecutionError> {
+    Address::from_str(address).map_err(|_| {
+        ExecutionError::new(
+            node_id,
+            ErrorKind::InvalidAddress,
+            format!("{} is not an address", address),
+        )
+    })
+}
+
+/// What a `QUERY` node makes of the results of its reads.
+enum QueryPlan {
+    Call(Function),
+    Balance,
+    PriceFeed { feed: Address, max_age: U256 },
+    AaveAccountData,
+}
+
+/// Works out what a `QUERY` node has to read, without reading anything yet, so the reads of
+/// several queries can be batched.
+fn plan_query(
+    data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<(QueryPlan, Vec<Read>), ExecutionError> {
+    let query_type = data.query_type.ok_or_else(|| {
+        ExecutionError::new(node_id, ErrorKind::MissingNodeData, "query_type is not set")
+    })?;
+
+    match query_type {
+        QueryType::Call => {
+            let (target, function, data) = contract_call(data, vars, node_id)?;
+            Ok((QueryPlan::Call(function), vec![Read::Call { target, data }]))
+        },
+        QueryType::Balance => {
+            let account = query_account(data, node_id)?;
+            Ok((QueryPlan::Balance, vec![Read::Balance(account)]))
+        },
+        QueryType::PriceFeed => {
+            let feed = required_field(&data.feed_address, "feed_address", node_id)?;
+            let feed = parse_address(feed, node_id)?;
+            let max_age = match optional_amount(&data.max_age, vars, node_id)? {
+                Some(max_age) => max_age,
+                None => price_feed::default_max_age().map(U256::from).map_err(|e| {
+                    ExecutionError::new(node_id, ErrorKind::InvalidAmount, format!("{:#}", e))
+                })?,
+            };
+            let reads = price_feed::reads(feed).map_err(|e| query_failed(node_id, &e))?;
+            Ok((QueryPlan::PriceFeed { feed, max_age }, reads))
+        },
+        QueryType::AaveAccountData => {
+            let pool = pool_address(data, node_id)?;
+            let account = query_account(data, node_id)?;
+            let read =
+                aave::account_data_read(pool, account).map_err(|e| query_failed(node_id, &e))?;
+            Ok((QueryPlan::AaveAccountData, vec![read]))
+        },
+    }
+}
+
+/// The `account` a query reads, the signing account when unset.
+fn query_account(data: &NodeData, node_id: u32) -> Result<Address, ExecutionError> {
+    match data.account.as_deref().filter(|account| !account.is_empty()) {
+        Some(account) => parse_address(account, node_id),
+        None => chain::account().map_err(|e| query_failed(node_id, &e)),
+    }
+}
+
+/// The node's `pool_address`, or `AAVE_POOL_ADDRESS` when unset.
+pub(crate) fn pool_address(data: &NodeData, node_id: u32) -> Result<Address, ExecutionError> {
+    match data.pool_address.as_deref().filter(|pool| !pool.is_empty()) {
+        Some(pool) => parse_address(pool, node_id),
+        None => aave::pool_address().map_err(|e| {
+            ExecutionError::new(node_id, ErrorKind::InvalidAddress, format!("{:#}", e))
+        }),
+    }
+}
+
+/// Turns the results of a query's reads into the values it publishes as `$<node id>.<name>`.
+/// The first one is also stored in the node's `result` variable when it names one.
+fn query_results(
+    plan: QueryPlan, results: Vec<anyhow::Result<Vec<u8>>>, node_id: u32,
+) -> Result<Vec<(String, Value)>, ExecutionError> {
+    let first = |results: Vec<anyhow::Result<Vec<u8>>>| {
+        results.into_iter().next().unwrap_or_else(|| Err(anyhow::anyhow!("nothing was read")))
+    };
+
+    match plan {
+        QueryPlan::Call(function) => first(results)
+            .and_then(|data| contract::decode(&function, &data))
+            .map_err(|e| query_failed(node_id, &e)),
+        QueryPlan::Balance => {
+            let balance = first(results)
+                .and_then(|data| Ok(ethabi::decode(&[ParamType::Uint(256)], &data)?))
+                .map_err(|e| query_failed(node_id, &e))?;
+            let balance = balance.into_iter().next().and_then(Token::into_uint).unwrap_or_default();
+
+            Ok(vec![("balance".to_string(), chain::u256_to_value(balance))])
+        },
+        QueryPlan::PriceFeed { feed, max_age } => {
+            let round =
+                price_feed::latest_round(feed, results).map_err(|e| query_failed(node_id, &e))?;
+            if U256::from(round.age) > max_age {
+                return Err(ExecutionError::new(
+                    node_id,
+                    ErrorKind::StalePrice,
+                    format!(
+                        "round {} of {:?} is {}s old, max_age is {}s",
+                        round.round_id, feed, round.age, max_age
+                    ),
+                ));
+            }
+
+            Ok(vec![
+                ("price".to_string(), Value::from(round.price)),
+                ("answer".to_string(), chain::u256_to_value(round.answer)),
+                ("decimals".to_string(), Value::from(round.decimals)),
+                ("round_id".to_string(), chain::u256_to_value(round.round_id)),
+                ("updated_at".to_string(), chain::u256_to_value(round.updated_at)),
+                ("age".to_string(), Value::from(round.age)),
+            ])
+        },
+        QueryPlan::AaveAccountData => {
+            let account = first(results)
+                .and_then(|data| aave::account_data(&data))
+                .map_err(|e| query_failed(node_id, &e))?;
+            let health_factor = account.health_factor().map_err(|e| query_failed(node_id, &e))?;
+
+            Ok(vec![
+                ("health_factor".to_string(), Value::from(health_factor)),
+                (
+                    "total_collateral_base".to_string(),
+                    chain::u256_to_value(account.total_collateral_base),
+                ),
+                ("total_debt_base".to_string(), chain::u256_to_value(account.total_debt_base)),
+                (
+                    "available_borrows_base".to_string(),
+                    chain::u256_to_value(account.available_borrows_base),
+                ),
+                (
+                    "current_liquidation_threshold".to_string(),
+                    chain::u256_to_value(account.current_liquidation_threshold),
+                ),
+                ("ltv".to_string(), chain::u256_to_value(account.ltv)),
+            ])
+        },
+    }
+}
+
+/// Reads, in one Multicall, what every `QUERY` child of `node_index` whose edge passes needs, so
+/// sibling queries don't each pay a round trip. Children that can't be planned yet are left to
+/// read on their own, and so is everything when there is nothing to batch or the batch fails.
+async fn prefetch_queries(
+    dag: &daggy::Dag<DagNode, DagEdge>, node_index: daggy::NodeIndex<u32>,
+    vars: &serde_json::Map<String, serde_json::Value>,
+) -> HashMap<daggy::NodeIndex<u32>, Vec<anyhow::Result<Vec<u8>>>> {
+    let mut planned = Vec::new();
+    for (edge_index, child_node_index) in dag.children(node_index).iter(dag) {
+        let child_node = &dag[child_node_index];
+        let data = match (&child_node.zap_type, &child_node.data) {
+            (ZapType::Query, Some(data)) => data,
+            _ => continue,
+        };
+
+        if let Some(condition) = &dag[edge_index].condition {
+            let passes = resolve_operand(&condition.left, vars, child_node.id)
+                .and_then(|left| {
+                    let right = resolve_operand(&condition.right, vars, child_node.id)?;
+                    evaluate_condition(&left, &right, &condition.operator, child_node.id)
+                })
+                .unwrap_or(false);
+            if !passes {
+                continue;
+            }
         }
-    });
+
+        if let Ok((_, reads)) = plan_query(data, vars, child_node.id) {
+            planned.push((child_node_index, reads));
+        }
+    }
+
+    if planned.len() < 2 {
+        return HashMap::new();
+    }
+
+    let reads: Vec<Read> = planned.iter().flat_map(|(_, reads)| reads.iter().cloned()).collect();
+    let mut results = match multicall::read_all(&reads).await {
+        Ok(results) => results.into_iter(),
+        Err(e) => {
+            log::warn!("Failed to prefetch {} queries: {:#}", planned.len(), e);
+            return HashMap::new();
+        },
+    };
+
+    planned
+        .into_iter()
+        .map(|(child_node_index, reads)| {
+            (child_node_index, results.by_ref().take(reads.len()).collect())
+        })
+        .collect()
 }
 
-fn evaluate_condition(a: &serde_json::Value, b: &serde_json::Value, operator: &str) -> bool {
+/// The contract, function and encoded arguments of a `CONTRACT_CALL` action or `CALL` query.
+pub(crate) fn contract_call(
+    data: &NodeData, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<(Address, Function, Vec<u8>), ExecutionError> {
+    let address = required_field(&data.contract_address, "contract_address", node_id)?;
+    let address = parse_address(address, node_id)?;
+    let abi = contract::abi(abi_source(data, node_id)?).map_err(|e| invalid_call(node_id, &e))?;
+    let function = required_field(&data.function, "function", node_id)?;
+    let function = contract::function(&abi, function).map_err(|e| invalid_call(node_id, &e))?;
+    let args = data
+        .args
+        .iter()
+        .flatten()
+        .map(|arg| resolve_arg(arg, vars, node_id))
+        .collect::<Result<Vec<_>, _>>()?;
+    let call_data = contract::encode(function, &args).map_err(|e| invalid_call(node_id, &e))?;
+
+    Ok((address, function.clone(), call_data))
+}
+
+/// A contract call's inline `abi` takes precedence over `abi_name`.
+fn abi_source(data: &NodeData, node_id: u32) -> Result<AbiSource<'_>, ExecutionError> {
+    if let Some(abi) = &data.abi {
+        return Ok(AbiSource::Inline(abi));
+    }
+
+    required_field(&data.abi_name, "abi_name", node_id).map(AbiSource::Registry)
+}
+
+/// Resolves the `$name` strings in a contract call argument, however deeply nested in arrays.
+fn resolve_arg(
+    arg: &Value, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<Value, ExecutionError> {
+    match arg {
+        Value::String(name) if name.starts_with('$') => resolve_operand(name, vars, node_id),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| resolve_arg(item, vars, node_id))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        _ => Ok(arg.clone()),
+    }
+}
+
+pub(crate) fn optional_amount(
+    amount: &Option<String>, vars: &serde_json::Map<String, serde_json::Value>, node_id: u32,
+) -> Result<Option<U256>, ExecutionError> {
+    match amount.as_deref().filter(|amount| !amount.is_empty()) {
+        Some(amount) => resolve_amount(amount, vars, node_id).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn transaction_failed(node_id: u32, error: &anyhow::Error) -> ExecutionError {
+    ExecutionError::new(node_id, ErrorKind::TransactionFailed, format!("{:#}", error))
+}
+
+fn query_failed(node_id: u32, error: &anyhow::Error) -> ExecutionError {
+    ExecutionError::new(node_id, ErrorKind::QueryFailed, format!("{:#}", error))
+}
+
+fn invalid_call(node_id: u32, error: &anyhow::Error) -> ExecutionError {
+    ExecutionError::new(node_id, ErrorKind::InvalidCall, format!("{:#}", error))
+}
+
+fn evaluate_condition(
+    a: &serde_json::Value, b: &serde_json::Value, operator: &str, node_id: u32,
+) -> Result<bool, ExecutionError> {
     if a.is_f64() && b.is_f64() {
-        return check_condition_oprator(a.as_f64().unwrap(), b.as_f64().unwrap(), operator);
+        return check_condition_oprator(a.as_f64().unwrap(), b.as_f64().unwrap(), operator, node_id);
     } else if a.is_i64() && b.is_i64() {
-        return check_condition_oprator(a.as_i64().unwrap(), b.as_i64().unwrap(), operator);
+        return check_condition_oprator(a.as_i64().unwrap(), b.as_i64().unwrap(), operator, node_id);
+    } else if let (Some(a), Some(b)) = (unsigned(a), unsigned(b)) {
+        // Amounts above `u64::MAX` are published as decimal strings.
+        return check_condition_oprator(a, b, operator, node_id);
+    } else if a.is_number() && b.is_number() {
+        // Mixed integers and floats, like a price against an integer literal, compare as floats.
+        return check_condition_oprator(a.as_f64().unwrap(), b.as_f64().unwrap(), operator, node_id);
     } else if a.is_boolean() && b.is_boolean() {
-        return check_condition_oprator(a.as_bool().unwrap(), b.as_bool().unwrap(), operator);
+        return check_condition_oprator(
+            a.as_bool().unwrap(),
+            b.as_bool().unwrap(),
+            operator,
+            node_id,
+        );
     } else if a.is_string() && b.is_string() {
-        return check_condition_oprator(a.as_str().unwrap(), b.as_str().unwrap(), operator);
+        return check_condition_oprator(a.as_str().unwrap(), b.as_str().unwrap(), operator, node_id);
     }
 
-    return false;
+    return Err(ExecutionError::new(
+        node_id,
+        ErrorKind::TypeMismatch,
+        format!("can't compare {} {} {}", a, operator, b),
+    ));
+}
+
+/// An unsigned integer, whether a JSON number or a decimal string like the ones `u256_to_value`
+/// publishes amounts above `u64::MAX` as.
+fn unsigned(value: &Value) -> Option<U256> {
+    match value {
+        Value::Number(number) => number.as_u64().map(U256::from),
+        Value::String(digits)
+            if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) =>
+        {
+            U256::from_dec_str(digits).ok()
+        },
+        _ => None,
+    }
 }
 
-fn check_condition_oprator<T: PartialOrd>(a: T, b: T, operator: &str) -> bool {
-    match operator {
+fn check_condition_oprator<T: PartialOrd>(
+    a: T, b: T, operator: &str, node_id: u32,
+) -> Result<bool, ExecutionError> {
+    Ok(match operator {
         "==" => a == b,
         "!=" => a != b,
         ">" => a > b,
         ">=" => a >= b,
         "<" => a < b,
         "<=" => a <= b,
-        _ => panic!("Unknown operator: {}", operator),
-    }
+        _ => return Err(unknown_operator(operator, node_id)),
+    })
+}
+
+fn unknown_operator(operator: &str, node_id: u32) -> ExecutionError {
+    ExecutionError::new(
+        node_id,
+        ErrorKind::UnknownOperator,
+        format!("unknown operator: {}", operator),
+    )
 }
 
 fn normalize_value(s: String) -> Value {
@@ -338,145 +962,255 @@ fn normalize_value(s: String) -> Value {
         return Value::Number(Number::from(i));
     } else if let Ok(i) = s.parse::<u64>() {
         return Value::Number(Number::from(i));
-    } else if let Ok(f) = s.parse::<f64>() {
-        return Value::Number(Number::from_f64(f).unwrap());
+    } else if !s.is_empty() && s.bytes().all(|byte| byte.is_ascii_digit()) {
+        // Integers beyond `u64` stay exact, as the decimal strings amounts are published as.
+        return Value::String(s);
+    } else if let Some(f) = s.parse::<f64>().ok().and_then(Number::from_f64) {
+        return Value::Number(f);
     }
 
     return Value::String(s);
 }
 
 fn evaluate_arithmetic(
-    a: &serde_json::Value,
-    b: &serde_json::Value,
-    operator: &str,
-) -> serde_json::Value {
-    if a.is_f64() && b.is_f64() {
-        let tmp = evaluate_arithmetic_oprator(a.as_f64().unwrap(), b.as_f64().unwrap(), operator);
-        return Value::Number(Number::from_f64(tmp).unwrap());
-    } else if a.is_i64() && b.is_i64() {
-        let tmp = evaluate_arithmetic_oprator(a.as_i64().unwrap(), b.as_i64().unwrap(), operator);
-        return Value::Number(Number::from(tmp));
-    } else if a.is_u64() && b.is_u64() {
-        let tmp = evaluate_arithmetic_oprator(a.as_u64().unwrap(), b.as_u64().unwrap(), operator);
-        return Value::Number(Number::from(tmp));
+    a: &serde_json::Value, b: &serde_json::Value, operator: &str, node_id: u32,
+) -> Result<serde_json::Value, ExecutionError> {
+    if a.is_i64() && b.is_i64() {
+        let tmp = evaluate_arithmetic_oprator(
+            a.as_i64().unwrap(),
+            b.as_i64().unwrap(),
+            operator,
+            node_id,
+        );
+        // Unsigned operands that overflow an i64 get another go as U256 below.
+        match tmp {
+            Ok(tmp) => return Ok(Value::Number(Number::from(tmp))),
+            Err(e) if e.kind != ErrorKind::Overflow || !(a.is_u64() && b.is_u64()) => {
+                return Err(e)
+            },
+            Err(_) => {},
+        }
+    }
+
+    if let (Some(a), Some(b)) = (unsigned(a), unsigned(b)) {
+        return evaluate_u256_oprator(a, b, operator, node_id).map(chain::u256_to_value);
+    } else if a.is_number() && b.is_number() {
+        // Floats, and integers mixed with floats, are computed as floats, the way conditions
+        // compare them.
+        let tmp =
+            evaluate_float_oprator(a.as_f64().unwrap(), b.as_f64().unwrap(), operator, node_id)?;
+        return Number::from_f64(tmp).map(Value::Number).ok_or_else(|| {
+            ExecutionError::new(
+                node_id,
+                ErrorKind::TypeMismatch,
+                format!("{} {} {} is not a finite number", a, operator, b),
+            )
+        });
     }
 
-    return Value::Null;
+    return Err(ExecutionError::new(
+        node_id,
+        ErrorKind::TypeMismatch,
+        format!("can't evaluate {} {} {}", a, operator, b),
+    ));
 }
 
+/// Integer arithmetic that fails the node on overflow rather than panicking or wrapping, so a
+/// result can't turn into a huge amount further down the graph.
 fn evaluate_arithmetic_oprator<
-    T: Add<Output = T>
-        + Rem<Output = T>
-        + Sub<Output = T>
-        + Mul<Output = T>
-        + Div<Output = T>
-        + Zero<Output = T>
-        + PartialEq,
+    T: CheckedAdd + CheckedSub + CheckedMul + CheckedDiv + CheckedRem + Zero + Display,
 >(
     a: T,
     b: T,
     operator: &str,
-) -> T {
-    if operator == "/" && b == T::zero() {
-        panic!("Division by zero");
+    node_id: u32,
+) -> Result<T, ExecutionError> {
+    if (operator == "/" || operator == "%") && b.is_zero() {
+        return Err(ExecutionError::new(node_id, ErrorKind::DivisionByZero, "division by zero"));
+    }
+
+    let result = match operator {
+        "+" => a.checked_add(&b),
+        "-" => a.checked_sub(&b),
+        "*" => a.checked_mul(&b),
+        "/" => a.checked_div(&b),
+        "%" => a.checked_rem(&b),
+        _ => return Err(unknown_operator(operator, node_id)),
+    };
+    result.ok_or_else(|| {
+        ExecutionError::new(
+            node_id,
+            ErrorKind::Overflow,
+            format!("{} {} {} is out of range", a, operator, b),
+        )
+    })
+}
+
+/// `evaluate_arithmetic_oprator` for amounts too large for a `u64`. U256 has its own checked
+/// operations rather than the `num` traits.
+fn evaluate_u256_oprator(
+    a: U256, b: U256, operator: &str, node_id: u32,
+) -> Result<U256, ExecutionError> {
+    if (operator == "/" || operator == "%") && b.is_zero() {
+        return Err(ExecutionError::new(node_id, ErrorKind::DivisionByZero, "division by zero"));
+    }
+
+    let result = match operator {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" => a.checked_div(b),
+        "%" => a.checked_rem(b),
+        _ => return Err(unknown_operator(operator, node_id)),
+    };
+    result.ok_or_else(|| {
+        ExecutionError::new(
+            node_id,
+            ErrorKind::Overflow,
+            format!("{} {} {} is out of range", a, operator, b),
+        )
+    })
+}
+
+fn evaluate_float_oprator(
+    a: f64, b: f64, operator: &str, node_id: u32,
+) -> Result<f64, ExecutionError> {
+    if (operator == "/" || operator == "%") && b == 0.0 {
+        return Err(ExecutionError::new(node_id, ErrorKind::DivisionByZero, "division by zero"));
     }
 
-    match operator {
+    Ok(match operator {
         "+" => a + b,
         "-" => a - b,
         "*" => a * b,
         "/" => a / b,
         "%" => a % b,
-        _ => panic!("Unknown operator: {}", operator),
-    }
+        _ => return Err(unknown_operator(operator, node_id)),
+    })
 }
 
-pub async fn swap_exact_eth_for_tokens(from_address: String, to_address: String, from_amount: u64) {
-    let provider_url = env::var("PROVIDER_URL").expect("PROVIDER_URL must be set");
-    let http = web3::transports::Http::new(&provider_url).unwrap();
-    let web3s = web3::Web3::new(http);
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-    let account_address = env::var("ACCOUNT_ADDRESS").expect("ACCOUNT_ADDRESS must be set");
-    let account = H160::from_str(&account_address).expect("Invalid account address");
+    use super::*;
 
-    let router02_address = env::var("ROUTER02_ADDRESS").expect("ROUTER02_ADDRESS must be set");
-    let router02_addr = Address::from_str(&router02_address).expect("Invalid router02 address");
-    let router02_contract =
-        Contract::from_json(web3s.eth(), router02_addr, include_bytes!("./router02_abi.json"))
-            .unwrap();
+    fn vars(vars: Value) -> serde_json::Map<String, Value> {
+        serde_json::from_value(vars).unwrap()
+    }
 
-    let from_address = Address::from_str(&from_address).expect("Invalid from address");
-    let to_address = Address::from_str(&to_address).expect("Invalid to address");
+    /// Parses a graph of a root and arithmetic nodes that each add one to `$x`, with `edges` as
+    /// `(parent, child)` pairs.
+    fn counting_graph(
+        node_ids: &[u32], edges: &[(u32, u32)],
+    ) -> (daggy::Dag<DagNode, DagEdge>, daggy::NodeIndex<u32>) {
+        let node = |id: u32, zap_type: &str| {
+            let children: Vec<_> = edges
+                .iter()
+                .filter(|(parent, _)| *parent == id)
+                .map(|(_, child)| json!({ "id": child }))
+                .collect();
+            let data = json!({ "left": "$x", "operator": "+", "right": "1", "result": "$x" });
+            json!({ "id": id, "zap_type": zap_type, "children": children, "data": data })
+        };
 
-    let valid_timestamp = get_valid_timestamp(300000);
+        let mut nodes = vec![node(0, "ROOT")];
+        nodes.extend(node_ids.iter().map(|id| node(*id, "ARITHMETIC")));
+        parse(serde_json::from_value(json!(nodes)).unwrap()).unwrap()
+    }
 
-    let out_gas_estimate = router02_contract
-        .estimate_gas(
-            "swapExactETHForTokens",
-            (
-                U256::from(from_amount),
-                vec![from_address, to_address],
-                account,
-                U256::from_dec_str(&valid_timestamp.to_string()).unwrap(),
-            ),
-            account,
-            Options {
-                gas: Some(500_000.into()),
-                ..Default::default()
-            },
-        )
-        .await
-        .expect("Failed to estimate gas");
-
-    let gas_price = web3s.eth().gas_price().await.expect("Failed to get gas price");
-
-    let data = router02_contract
-        .abi()
-        .function("swapExactETHForTokens")
-        .unwrap()
-        .encode_input(
-            &(
-                U256::from(from_amount),
-                vec![from_address, to_address],
-                account,
-                U256::from_dec_str(&valid_timestamp.to_string()).unwrap(),
-            )
-                .into_tokens(),
-        )
-        .expect("Failed to swapExactETHForTokens");
-
-    let nonce = web3s.eth().transaction_count(account, None).await.unwrap() + valid_timestamp;
-    let transact_obj = TransactionParameters {
-        nonce: Some(nonce),
-        to: Some(router02_addr),
-        value: U256::exp10(18).checked_div(20.into()).unwrap(),
-        gas_price: Some(gas_price),
-        gas: out_gas_estimate,
-        data: Bytes(data),
-        ..Default::default()
-    };
+    #[tokio::test]
+    async fn walk_visits_a_join_once() {
+        let (dag, root) = counting_graph(&[1, 2, 3, 4], &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 4)]);
+        let inputs = vars(json!({ "$x": 0 }));
+        let mut report = RunReport::new(None, "test".to_string(), true, inputs.clone());
 
-    let private_key_string = env::var("PRIVATE_KEY").expect("PRIVATE_KEY must be set");
-    let private_key: SecretKey = private_key_string.parse().expect("Invalid private key");
-    let signed_transaction = web3s
-        .accounts()
-        .sign_transaction(transact_obj, &private_key)
-        .await
-        .expect("Failed to sign transaction");
+        walk(&dag, root, inputs, &mut report).await.unwrap();
 
-    let result = web3s
-        .eth()
-        .send_raw_transaction(signed_transaction.raw_transaction)
-        .await
-        .expect("Failed to send transaction");
+        let visited: Vec<_> = report.steps.iter().map(|step| step.node_id).collect();
+        assert_eq!(visited.len(), 5);
+        assert_eq!(visited.iter().filter(|id| **id == 3).count(), 1);
+        let below_join = report.steps.iter().find(|step| step.node_id == 4).unwrap();
+        assert_eq!(below_join.vars["$x"], json!(3));
+    }
 
-    log::info!("Transaction successful with hash: {:?}", result);
-}
+    fn arithmetic(left: Value, operator: &str, right: Value) -> Result<Value, ExecutionError> {
+        evaluate_arithmetic(&left, &right, operator, 1)
+    }
 
-fn get_valid_timestamp(future_millis: u128) -> u128 {
-    let start = SystemTime::now();
-    let since_epoch = start.duration_since(UNIX_EPOCH).unwrap();
-    let time_millis = since_epoch.as_millis().checked_add(future_millis).unwrap();
+    fn condition(left: Value, operator: &str, right: Value) -> bool {
+        evaluate_condition(&left, &right, operator, 1).unwrap()
+    }
 
-    time_millis
+    #[test]
+    fn arithmetic_falls_back_to_u256_on_overflow() {
+        let max = json!(u64::MAX);
+        assert_eq!(arithmetic(max.clone(), "+", json!(1)).unwrap(), json!("18446744073709551616"));
+        assert_eq!(
+            arithmetic(max.clone(), "*", max).unwrap(),
+            json!("340282366920938463426481119284349108225")
+        );
+        let above_u64 = json!("18446744073709551616");
+        assert_eq!(arithmetic(above_u64, "-", json!(1)).unwrap(), json!(u64::MAX));
+    }
+
+    #[test]
+    fn arithmetic_fails_on_overflow_it_cannot_fall_back_from() {
+        let overflow = |left: Value, operator: &str, right: Value| {
+            arithmetic(left, operator, right).unwrap_err().kind
+        };
+        assert_eq!(overflow(json!(i64::MIN), "-", json!(1)), ErrorKind::Overflow);
+        assert_eq!(overflow(json!(U256::MAX.to_string()), "+", json!(1)), ErrorKind::Overflow);
+        assert_eq!(overflow(json!(0), "-", json!(u64::MAX)), ErrorKind::Overflow);
+    }
+
+    #[test]
+    fn arithmetic_rejects_division_by_zero() {
+        let operands = [(json!(1), json!(0)), (json!(1.5), json!(0.0)), (json!(2), json!(0.0))];
+        for (left, right) in operands {
+            for operator in ["/", "%"] {
+                let error = arithmetic(left.clone(), operator, right.clone()).unwrap_err();
+                assert_eq!(error.kind, ErrorKind::DivisionByZero);
+            }
+        }
+        let error = arithmetic(json!(U256::MAX.to_string()), "/", json!(0)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn arithmetic_mixes_integers_and_floats_like_conditions() {
+        assert_eq!(arithmetic(json!(2), "*", json!(1.5)).unwrap(), json!(3.0));
+        assert_eq!(arithmetic(json!(0.5), "+", json!(-1)).unwrap(), json!(-0.5));
+        assert_eq!(arithmetic(json!(-1), "+", json!(u64::MAX)).unwrap(), json!(u64::MAX as f64));
+        assert!(condition(json!(2), "<", json!(2.5)));
+        assert!(condition(json!(3.0), "==", json!(3)));
+
+        let error = arithmetic(json!("18446744073709551616"), "*", json!(1.5)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+        assert_eq!(
+            evaluate_condition(&json!("18446744073709551616"), &json!(1.5), ">", 1)
+                .unwrap_err()
+                .kind,
+            ErrorKind::TypeMismatch
+        );
+    }
+
+    #[test]
+    fn resolve_amount_reads_literals_and_variables() {
+        let vars = vars(json!({ "$small": 5, "$large": U256::MAX.to_string() }));
+        assert_eq!(resolve_amount("42", &vars, 1).unwrap(), U256::from(42));
+        assert_eq!(resolve_amount("$small", &vars, 1).unwrap(), U256::from(5));
+        assert_eq!(resolve_amount("$large", &vars, 1).unwrap(), U256::MAX);
+    }
+
+    #[test]
+    fn resolve_amount_rejects_empty_strings() {
+        let vars = vars(json!({ "$empty": "", "$negative": -1 }));
+        assert_eq!(resolve_amount("", &vars, 1).unwrap_err().kind, ErrorKind::MissingVariable);
+        assert_eq!(resolve_amount("$empty", &vars, 1).unwrap_err().kind, ErrorKind::InvalidAmount);
+        assert_eq!(
+            resolve_amount("$negative", &vars, 1).unwrap_err().kind,
+            ErrorKind::InvalidAmount
+        );
+    }
 }
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+
+use serde::*;
+
+use crate::dag::{Condition, Node};
+
+#[derive(Debug, Serialize, Clone)]
+pub struct NodeChange {
+    pub before: Node,
+    pub after: Node,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeRef {
+    pub from: u32,
+    pub to: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeChange {
+    pub from: u32,
+    pub to: u32,
+    pub before: Option<Condition>,
+    pub after: Option<Condition>,
+}
+
+/// What it takes to turn one graph into another. Nodes are matched by id and edges by their
+/// `(from, to)` pair; a node only counts as changed if its type or data differ, since changes to
+/// its children already show up as edge changes.
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct Diff {
+    pub nodes_added: Vec<Node>,
+    pub nodes_removed: Vec<Node>,
+    pub nodes_changed: Vec<NodeChange>,
+    pub edges_added: Vec<EdgeRef>,
+    pub edges_removed: Vec<EdgeRef>,
+    pub edges_changed: Vec<EdgeChange>,
+}
+
+pub fn diff(before: &[Node], after: &[Node]) -> Diff {
+    let mut diff = Diff::default();
+
+    let before_nodes: BTreeMap<u32, &Node> = before.iter().map(|node| (node.id, node)).collect();
+    let after_nodes: BTreeMap<u32, &Node> = after.iter().map(|node| (node.id, node)).collect();
+
+    for (id, node) in &after_nodes {
+        match before_nodes.get(id) {
+            None => diff.nodes_added.push((*node).clone()),
+            Some(old) if old.zap_type != node.zap_type || old.data != node.data => {
+                diff.nodes_changed
+                    .push(NodeChange { before: (*old).clone(), after: (*node).clone() })
+            },
+            Some(_) => {},
+        }
+    }
+
+    for (id, node) in &before_nodes {
+        if !after_nodes.contains_key(id) {
+            diff.nodes_removed.push((*node).clone());
+        }
+    }
+
+    let before_edges = edges(before);
+    let after_edges = edges(after);
+
+    for ((from, to), condition) in &after_edges {
+        match before_edges.get(&(*from, *to)) {
+            None => diff.edges_added.push(EdgeRef {
+                from: *from,
+                to: *to,
+                condition: (*condition).clone(),
+            }),
+            Some(old) if old != condition => diff.edges_changed.push(EdgeChange {
+                from: *from,
+                to: *to,
+                before: (*old).clone(),
+                after: (*condition).clone(),
+            }),
+            Some(_) => {},
+        }
+    }
+
+    for ((from, to), condition) in &before_edges {
+        if !after_edges.contains_key(&(*from, *to)) {
+            diff.edges_removed.push(EdgeRef {
+                from: *from,
+                to: *to,
+                condition: (*condition).clone(),
+            });
+        }
+    }
+
+    diff
+}
+
+fn edges(nodes: &[Node]) -> BTreeMap<(u32, u32), &Option<Condition>> {
+    nodes
+        .iter()
+        .flat_map(|node| {
+            node.children.iter().flatten().map(move |edge| ((node.id, edge.id), &edge.condition))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn nodes(nodes: Value) -> Vec<Node> { serde_json::from_value(nodes).unwrap() }
+
+    fn arithmetic(id: u32, left: &str, children: Value) -> Value {
+        let data = json!({ "left": left, "operator": "+", "right": "2", "result": "$x" });
+        json!({ "id": id, "zap_type": "ARITHMETIC", "children": children, "data": data })
+    }
+
+    fn condition(right: &str) -> Value { json!({ "left": "$x", "operator": ">", "right": right }) }
+
+    #[test]
+    fn identical_graphs_have_no_changes() {
+        let graph = nodes(json!([
+            { "id": 0, "zap_type": "ROOT", "children": [{ "id": 1 }] },
+            arithmetic(1, "1", json!([])),
+        ]));
+
+        let diff = diff(&graph, &graph);
+        assert!(diff.nodes_added.is_empty() && diff.nodes_removed.is_empty());
+        assert!(diff.nodes_changed.is_empty());
+        assert!(diff.edges_added.is_empty() && diff.edges_removed.is_empty());
+        assert!(diff.edges_changed.is_empty());
+    }
+
+    #[test]
+    fn matches_nodes_by_id() {
+        let before = nodes(json!([
+            { "id": 0, "zap_type": "ROOT", "children": [{ "id": 1 }, { "id": 2 }] },
+            arithmetic(1, "1", json!([])),
+            arithmetic(2, "1", json!([])),
+        ]));
+        let after = nodes(json!([
+            { "id": 0, "zap_type": "ROOT", "children": [{ "id": 1 }, { "id": 3 }] },
+            arithmetic(1, "5", json!([])),
+            arithmetic(3, "1", json!([])),
+        ]));
+
+        let diff = diff(&before, &after);
+        assert_eq!(diff.nodes_added.iter().map(|node| node.id).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(diff.nodes_removed.iter().map(|node| node.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(diff.nodes_changed.len(), 1);
+        assert_eq!(diff.nodes_changed[0].before.data, before[1].data);
+        assert_eq!(diff.nodes_changed[0].after.data, after[1].data);
+    }
+
+    #[test]
+    fn new_children_are_edge_changes_only() {
+        let before = nodes(json!([
+            { "id": 0, "zap_type": "ROOT", "children": [{ "id": 1 }] },
+            arithmetic(1, "1", json!([])),
+            arithmetic(2, "1", json!([])),
+        ]));
+        let after = nodes(json!([
+            { "id": 0, "zap_type": "ROOT", "children": [{ "id": 1 }] },
+            arithmetic(1, "1", json!([{ "id": 2 }])),
+            arithmetic(2, "1", json!([])),
+        ]));
+
+        let diff = diff(&before, &after);
+        assert!(diff.nodes_changed.is_empty());
+        assert_eq!(diff.edges_added.len(), 1);
+        assert_eq!((diff.edges_added[0].from, diff.edges_added[0].to), (1, 2));
+        assert!(diff.edges_removed.is_empty());
+    }
+
+    #[test]
+    fn reports_edges_by_their_ends() {
+        let before = nodes(json!([
+            {
+                "id": 0,
+                "zap_type": "ROOT",
+                "children": [{ "id": 1, "condition": condition("1") }, { "id": 2 }],
+            },
+            arithmetic(1, "1", json!([])),
+            arithmetic(2, "1", json!([])),
+        ]));
+        let after = nodes(json!([
+            {
+                "id": 0,
+                "zap_type": "ROOT",
+                "children": [{ "id": 1, "condition": condition("9") }],
+            },
+            arithmetic(1, "1", json!([])),
+            arithmetic(2, "1", json!([])),
+        ]));
+
+        let diff = diff(&before, &after);
+        assert!(diff.edges_added.is_empty());
+        assert_eq!(diff.edges_removed.len(), 1);
+        assert_eq!((diff.edges_removed[0].from, diff.edges_removed[0].to), (0, 2));
+        assert_eq!(diff.edges_changed.len(), 1);
+        let change = &diff.edges_changed[0];
+        assert_eq!((change.from, change.to), (0, 1));
+        assert_eq!(change.before.as_ref().map(|c| c.right.as_str()), Some("1"));
+        assert_eq!(change.after.as_ref().map(|c| c.right.as_str()), Some("9"));
+    }
+}
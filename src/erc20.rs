@@ -0,0 +1,161 @@
+use std::env;
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::contract::tokens::Tokenize;
+use web3::contract::{Contract, Options};
+use web3::transports::Http;
+use web3::types::{Address, U256};
+use web3::Web3;
+
+use crate::execution::{ActionOutcome, Submission};
+use crate::{chain, router};
+
+/// How much an automatic approval grants.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApprovalAmount {
+    /// Just what the action is about to spend, so every spend needs its own approval.
+    Exact,
+    /// `2^256 - 1`, so the spender never needs approving again.
+    Unlimited,
+}
+
+/// Where an action stands once `approve_spends` is through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Approval {
+    /// Nothing needed approving, or every approval was mined.
+    Ready,
+    /// A dry run built an approval it never sent, so the action's own transaction can't be
+    /// simulated or estimated.
+    Pending,
+    /// An approval reverted or was never confirmed, so the action can't go on.
+    Stopped,
+}
+
+/// Whether token-spending actions approve their spender on their own, from `AUTO_APPROVE`.
+/// Defaults to true.
+fn auto_approve() -> anyhow::Result<bool> {
+    match env::var("AUTO_APPROVE") {
+        Ok(auto_approve) => auto_approve.parse().context("Invalid AUTO_APPROVE"),
+        Err(_) => Ok(true),
+    }
+}
+
+/// `APPROVAL_AMOUNT` is either `exact` (the default) or `unlimited`.
+fn approval_amount() -> anyhow::Result<ApprovalAmount> {
+    match env::var("APPROVAL_AMOUNT").as_deref() {
+        Ok("exact") | Err(_) => Ok(ApprovalAmount::Exact),
+        Ok("unlimited") => Ok(ApprovalAmount::Unlimited),
+        Ok(other) => Err(anyhow::anyhow!("Invalid APPROVAL_AMOUNT: {}", other)),
+    }
+}
+
+pub fn contract(web3: &Web3<Http>, token: Address) -> anyhow::Result<Contract<Http>> {
+    Ok(Contract::from_json(web3.eth(), token, include_bytes!("./erc20_abi.json"))?)
+}
+
+pub async fn balance_of(
+    web3: &Web3<Http>, token: Address, owner: Address,
+) -> anyhow::Result<U256> {
+    contract(web3, token)?
+        .query("balanceOf", owner, None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to read the balance of {:?} in {:?}", owner, token))
+}
+
+pub async fn total_supply(web3: &Web3<Http>, token: Address) -> anyhow::Result<U256> {
+    contract(web3, token)?
+        .query("totalSupply", (), None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to read the total supply of {:?}", token))
+}
+
+pub async fn allowance(
+    web3: &Web3<Http>, token: Address, owner: Address, spender: Address,
+) -> anyhow::Result<U256> {
+    contract(web3, token)?
+        .query("allowance", (owner, spender), None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to read the allowance of {:?} on {:?}", spender, token))
+}
+
+async fn approval(
+    web3: &Web3<Http>, token: Address, spender: Address, amount: U256, dry_run: bool,
+) -> anyhow::Result<Submission> {
+    let data = contract(web3, token)?
+        .abi()
+        .function("approve")?
+        .encode_input(&(spender, amount).into_tokens())
+        .context("Failed to encode approve")?;
+    let parameters = chain::transaction(web3, token, U256::zero(), data).await?;
+
+    chain::submit(web3, parameters, dry_run).await
+}
+
+/// Makes sure `spender` may spend `amount` of `token` from the signing account before an action
+/// does so, approving it if needed. Returns the approval, or `None` if the allowance already
+/// covers `amount` or `AUTO_APPROVE` is off.
+pub async fn ensure_allowance(
+    web3: &Web3<Http>, token: Address, spender: Address, amount: U256, dry_run: bool,
+) -> anyhow::Result<Option<Submission>> {
+    if !auto_approve()? {
+        return Ok(None);
+    }
+
+    let current = allowance(web3, token, chain::account()?, spender).await?;
+    if current >= amount {
+        return Ok(None);
+    }
+
+    let approved = match approval_amount()? {
+        ApprovalAmount::Exact => amount,
+        ApprovalAmount::Unlimited => U256::MAX,
+    };
+    log::info!("Approving {:?} for {} of {:?}, allowance is {}", spender, approved, token, current);
+    approval(web3, token, spender, approved, dry_run).await.map(Some)
+}
+
+/// Makes sure `spender` may spend each `(token, amount)` of `spends` before an action does so,
+/// adding every approval it sends to `outcome`.
+pub async fn approve_spends(
+    web3: &Web3<Http>, spender: Address, spends: &[(Address, U256)], dry_run: bool,
+    outcome: &mut ActionOutcome,
+) -> anyhow::Result<Approval> {
+    let mut approval = Approval::Ready;
+    for (token, amount) in spends {
+        if let Some(submission) = ensure_allowance(web3, *token, spender, *amount, dry_run).await? {
+            let stopped = submission.reverted() || submission.unconfirmed();
+            outcome.submissions.push(submission);
+            if stopped {
+                return Ok(Approval::Stopped);
+            }
+            if dry_run {
+                approval = Approval::Pending;
+            }
+        }
+    }
+
+    Ok(approval)
+}
+
+/// Approves `spender`, the router unless set, for exactly `amount` of `token`, whatever the
+/// current allowance. Publishes `allowance` next to the usual transaction outputs.
+pub async fn approve(
+    token: &str, spender: Option<&str>, amount: U256, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let token = Address::from_str(token).context("Invalid token address")?;
+    let spender = match spender.filter(|spender| !spender.is_empty()) {
+        Some(spender) => Address::from_str(spender).context("Invalid spender address")?,
+        None => router::address()?,
+    };
+
+    let submission = approval(&web3s, token, spender, amount, dry_run).await?;
+
+    let mut outcome = ActionOutcome::default();
+    outcome.outputs.extend(chain::submission_outputs(&submission));
+    outcome.outputs.insert("allowance".to_string(), chain::u256_to_value(amount));
+    outcome.submissions.push(submission);
+
+    Ok(outcome)
+}
@@ -0,0 +1,329 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::*;
+use uuid::Uuid;
+use web3::types::{Address, Bytes, TransactionParameters, TransactionReceipt, H256, U256, U64};
+
+use crate::dag::Condition;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum ErrorKind {
+    #[serde(rename = "MISSING_VARIABLE")]
+    MissingVariable,
+    #[serde(rename = "UNKNOWN_OPERATOR")]
+    UnknownOperator,
+    #[serde(rename = "DIVISION_BY_ZERO")]
+    DivisionByZero,
+    #[serde(rename = "OVERFLOW")]
+    Overflow,
+    #[serde(rename = "TYPE_MISMATCH")]
+    TypeMismatch,
+    #[serde(rename = "MISSING_NODE_DATA")]
+    MissingNodeData,
+    #[serde(rename = "UNKNOWN_ACTION_TYPE")]
+    UnknownActionType,
+    #[serde(rename = "INVALID_AMOUNT")]
+    InvalidAmount,
+    #[serde(rename = "INVALID_VALUE")]
+    InvalidValue,
+    #[serde(rename = "INVALID_ADDRESS")]
+    InvalidAddress,
+    #[serde(rename = "RECIPIENT_NOT_ALLOWED")]
+    RecipientNotAllowed,
+    #[serde(rename = "INVALID_CALL")]
+    InvalidCall,
+    #[serde(rename = "SLIPPAGE_EXCEEDED")]
+    SlippageExceeded,
+    #[serde(rename = "TRANSACTION_FAILED")]
+    TransactionFailed,
+    #[serde(rename = "TRANSACTION_REVERTED")]
+    TransactionReverted,
+    #[serde(rename = "QUERY_FAILED")]
+    QueryFailed,
+    #[serde(rename = "STALE_PRICE")]
+    StalePrice,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExecutionError {
+    pub node_id: u32,
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ExecutionError {
+    pub fn new(node_id: u32, kind: ErrorKind, message: impl Into<String>) -> Self {
+        ExecutionError { node_id, kind, message: message.into() }
+    }
+}
+
+impl std::fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node {}: {:?}: {}", self.node_id, self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Step {
+    pub node_id: u32,
+    pub vars: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SkippedEdge {
+    pub from: u32,
+    pub to: u32,
+    pub condition: Condition,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EvaluatedCondition {
+    pub from: u32,
+    pub to: u32,
+    pub left: serde_json::Value,
+    pub operator: String,
+    pub right: serde_json::Value,
+    pub passed: bool,
+}
+
+/// A transaction built by an action node. `hash` is only set once it has been broadcast, so a
+/// dry run lists exactly what would have been sent.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Transaction {
+    pub node_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Address>,
+    pub value: U256,
+    pub gas: U256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gas_price: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<U256>,
+    pub data: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<H256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_number: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gas_used: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_gas_price: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+}
+
+/// A transaction an action built and, unless this is a dry run, its hash and the receipt it was
+/// mined with. A sent transaction whose receipt never arrived has a hash but no receipt.
+#[derive(Debug)]
+pub struct Submission {
+    pub parameters: TransactionParameters,
+    pub hash: Option<H256>,
+    pub receipt: Option<TransactionReceipt>,
+    pub revert_reason: Option<String>,
+}
+
+impl Submission {
+    /// A transaction that was built but not sent, as in a dry run.
+    pub fn unsent(parameters: TransactionParameters) -> Self {
+        Submission { parameters, hash: None, receipt: None, revert_reason: None }
+    }
+
+    /// Whether the transaction was broadcast but it is unknown whether it was mined.
+    pub fn unconfirmed(&self) -> bool { self.hash.is_some() && self.receipt.is_none() }
+
+    /// Whether the transaction was mined and then reverted.
+    pub fn reverted(&self) -> bool {
+        self.receipt.as_ref().and_then(|receipt| receipt.status) == Some(U64::zero())
+    }
+}
+
+/// What an action node did: every transaction it built, in order, and the values it publishes to
+/// downstream nodes as `$<node id>.<name>`.
+#[derive(Debug, Default)]
+pub struct ActionOutcome {
+    pub submissions: Vec<Submission>,
+    pub outputs: serde_json::Map<String, serde_json::Value>,
+    /// Why the action stopped after building `submissions`, which still have to be recorded
+    /// before the node fails.
+    pub failure: Option<anyhow::Error>,
+}
+
+impl ActionOutcome {
+    /// Keeps the error of whatever followed the transactions already in the outcome as its
+    /// `failure`, rather than dropping them with it.
+    pub fn finish(mut self, result: anyhow::Result<()>) -> Self {
+        self.failure = result.err();
+        self
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum RunStatus {
+    #[serde(rename = "RUNNING")]
+    Running,
+    #[serde(rename = "SUCCEEDED")]
+    Succeeded,
+    #[serde(rename = "FAILED")]
+    Failed,
+}
+
+impl RunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Running => "RUNNING",
+            RunStatus::Succeeded => "SUCCEEDED",
+            RunStatus::Failed => "FAILED",
+        }
+    }
+}
+
+/// Everything that happened during one `dag::walk`, in the order it happened. This is also the
+/// record kept in the run history.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RunReport {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zap_id: Option<Uuid>,
+    /// Who or what started the run, e.g. `api` or the name of an external scheduler.
+    pub trigger: String,
+    pub status: RunStatus,
+    /// Dry runs build and estimate every transaction but never sign or broadcast them.
+    #[serde(default)]
+    pub dry_run: bool,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    pub inputs: serde_json::Map<String, serde_json::Value>,
+    pub steps: Vec<Step>,
+    /// Every edge condition that was checked, whether it let the walk through or not.
+    pub conditions: Vec<EvaluatedCondition>,
+    pub skipped_edges: Vec<SkippedEdge>,
+    pub transactions: Vec<Transaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ExecutionError>,
+}
+
+impl RunReport {
+    pub fn new(
+        zap_id: Option<Uuid>, trigger: String, dry_run: bool,
+        inputs: serde_json::Map<String, serde_json::Value>,
+    ) -> Self {
+        RunReport {
+            id: Uuid::new_v4(),
+            zap_id,
+            trigger,
+            status: RunStatus::Running,
+            dry_run,
+            started_at: Utc::now(),
+            finished_at: None,
+            inputs,
+            steps: Vec::new(),
+            conditions: Vec::new(),
+            skipped_edges: Vec::new(),
+            transactions: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn record(&mut self, node_id: u32, submission: &Submission) {
+        let parameters = &submission.parameters;
+        let receipt = submission.receipt.as_ref();
+        self.transactions.push(Transaction {
+            node_id,
+            to: parameters.to,
+            value: parameters.value,
+            gas: parameters.gas,
+            gas_price: parameters.gas_price,
+            nonce: parameters.nonce,
+            data: parameters.data.clone(),
+            hash: submission.hash,
+            block_number: receipt.and_then(|receipt| receipt.block_number),
+            status: receipt.and_then(|receipt| receipt.status),
+            gas_used: receipt.and_then(|receipt| receipt.gas_used),
+            effective_gas_price: receipt.and_then(|receipt| receipt.effective_gas_price),
+            revert_reason: submission.revert_reason.clone(),
+        });
+    }
+
+    pub fn finish(&mut self, result: Result<(), ExecutionError>) {
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+
+        self.status = if self.error.is_some() { RunStatus::Failed } else { RunStatus::Succeeded };
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+tokio::task_local! {
+    /// The journal of the run being walked, if the walk is running inside `Journal::scope`.
+    static JOURNAL: Journal;
+}
+
+/// Saves a run while it is being walked, so a crash or a lost request still leaves a record of
+/// every transaction that was broadcast.
+#[derive(Clone)]
+pub struct Journal {
+    save: Arc<dyn Fn(RunReport) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>,
+    /// The report as last saved, and the node running since.
+    latest: Arc<Mutex<(RunReport, u32)>>,
+}
+
+impl Journal {
+    pub fn new(
+        report: &RunReport,
+        save: impl Fn(RunReport) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync + 'static,
+    ) -> Self {
+        Journal { save: Arc::new(save), latest: Arc::new(Mutex::new((report.clone(), 0))) }
+    }
+
+    pub async fn save(&self, report: &RunReport) -> anyhow::Result<()> {
+        (self.save)(report.clone()).await
+    }
+
+    /// Runs `future`, a walk, with `checkpoint` and `record_sent` writing to this journal.
+    pub fn scope<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        JOURNAL.scope(self, future)
+    }
+
+    async fn checkpoint(&self, report: &RunReport, node_id: u32) {
+        *self.latest.lock().unwrap_or_else(PoisonError::into_inner) = (report.clone(), node_id);
+        if let Err(e) = self.save(report).await {
+            log::error!("Failed to save run {}: {:#}", report.id, e);
+        }
+    }
+
+    async fn sent(&self, submission: &Submission) {
+        let report = {
+            let mut latest = self.latest.lock().unwrap_or_else(PoisonError::into_inner);
+            let (report, node_id) = &mut *latest;
+            report.record(*node_id, submission);
+            report.clone()
+        };
+        if let Err(e) = self.save(&report).await {
+            log::error!("Failed to save transaction of run {}: {:#}", report.id, e);
+        }
+    }
+}
+
+/// Saves `report` as it stands while `node_id` runs, if the walk has a journal.
+pub async fn checkpoint(report: &RunReport, node_id: u32) {
+    if let Ok(journal) = JOURNAL.try_with(Journal::clone) {
+        journal.checkpoint(report, node_id).await;
+    }
+}
+
+/// Saves a transaction the moment it is broadcast, as part of the node running in the walk's
+/// journal. Until the action returns, that is the only record of it.
+pub async fn record_sent(submission: &Submission) {
+    if let Ok(journal) = JOURNAL.try_with(Journal::clone) {
+        journal.sent(submission).await;
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::Context;
+use serde_json::Value;
+use web3::contract::tokens::Tokenize;
+use web3::ethabi::Token;
+use web3::signing::keccak256;
+use web3::types::{Address, TransactionReceipt, H256, U256};
+
+use crate::erc20::Approval;
+use crate::execution::{ActionOutcome, Submission};
+use crate::{chain, erc20, router};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Change {
+    Add { amount_a_desired: U256, amount_b_desired: U256 },
+    Remove { liquidity: U256 },
+}
+
+/// A change to the signing account's position in the `token_a`/`token_b` pool. Without
+/// `token_b` the other side is ETH, which the router wraps and unwraps on its own. Unset minimums
+/// are derived from the expected amounts and the node's slippage.
+#[derive(Debug, Clone)]
+pub struct LiquidityRequest {
+    pub token_a: Address,
+    pub token_b: Option<Address>,
+    pub change: Change,
+    pub amount_a_min: Option<U256>,
+    pub amount_b_min: Option<U256>,
+}
+
+fn apply_slippage(amount: U256, slippage_bps: U256) -> U256 {
+    let max_bps = U256::from(10_000);
+    amount.saturating_mul(max_bps - std::cmp::min(slippage_bps, max_bps)) / max_bps
+}
+
+/// Adds or removes liquidity through the router, approving the tokens or LP tokens it spends
+/// first. Publishes `pair`, `amount_a`, `amount_b`, `liquidity` (minted or burned) and
+/// `lp_balance` next to the usual transaction outputs. A dry run that has to wait for an
+/// approval can't simulate the call, so it leaves out whatever only the call would tell.
+pub async fn execute(
+    request: LiquidityRequest, slippage_bps: U256, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let account = chain::account()?;
+    let (router02_addr, router02_contract) = router::router(&web3s)?;
+
+    let token_a = request.token_a;
+    let token_b = match request.token_b {
+        Some(token_b) => token_b,
+        None => router::weth(&router02_contract).await?,
+    };
+    let factory = router::factory(&web3s, &router02_contract).await?;
+    let pair = router::pair(&factory, token_a, token_b).await?;
+    let mut lp_balance =
+        if pair.is_zero() { U256::zero() } else { erc20::balance_of(&web3s, pair, account).await? };
+
+    let (expected_a, expected_b) = match request.change {
+        Change::Add { amount_a_desired, amount_b_desired } => (amount_a_desired, amount_b_desired),
+        Change::Remove { liquidity } => {
+            if pair.is_zero() {
+                anyhow::bail!("There is no pair for {:?} and {:?}", token_a, token_b);
+            }
+            let (reserve0, reserve1) = router::reserves(&web3s, pair).await?;
+            let (reserve_a, reserve_b) =
+                if token_a < token_b { (reserve0, reserve1) } else { (reserve1, reserve0) };
+            let total_supply = erc20::total_supply(&web3s, pair).await?;
+            if total_supply.is_zero() {
+                anyhow::bail!("Pair {:?} has no liquidity", pair);
+            }
+            (
+                liquidity.saturating_mul(reserve_a) / total_supply,
+                liquidity.saturating_mul(reserve_b) / total_supply,
+            )
+        },
+    };
+    let amount_a_min =
+        request.amount_a_min.unwrap_or_else(|| apply_slippage(expected_a, slippage_bps));
+    let amount_b_min =
+        request.amount_b_min.unwrap_or_else(|| apply_slippage(expected_b, slippage_bps));
+
+    let spends = match (request.change, request.token_b) {
+        (Change::Add { amount_a_desired, amount_b_desired }, Some(token_b)) => {
+            vec![(token_a, amount_a_desired), (token_b, amount_b_desired)]
+        },
+        (Change::Add { amount_a_desired, .. }, None) => vec![(token_a, amount_a_desired)],
+        (Change::Remove { liquidity }, _) => vec![(pair, liquidity)],
+    };
+    let mut outcome = ActionOutcome::default();
+    let result: anyhow::Result<()> = async {
+        let approval =
+            erc20::approve_spends(&web3s, router02_addr, &spends, dry_run, &mut outcome).await?;
+        if approval == Approval::Stopped {
+            return Ok(());
+        }
+        let pending = approval == Approval::Pending;
+
+        let deadline = router::deadline(&web3s).await?;
+        let (function_name, value, params) = match (request.change, request.token_b) {
+            (Change::Add { amount_a_desired, amount_b_desired }, Some(token_b)) => (
+                "addLiquidity",
+                U256::zero(),
+                (
+                    token_a,
+                    token_b,
+                    amount_a_desired,
+                    amount_b_desired,
+                    amount_a_min,
+                    amount_b_min,
+                    account,
+                    deadline,
+                )
+                    .into_tokens(),
+            ),
+            (Change::Add { amount_a_desired, amount_b_desired }, None) => (
+                "addLiquidityETH",
+                amount_b_desired,
+                (token_a, amount_a_desired, amount_a_min, amount_b_min, account, deadline)
+                    .into_tokens(),
+            ),
+            (Change::Remove { liquidity }, Some(token_b)) => (
+                "removeLiquidity",
+                U256::zero(),
+                (token_a, token_b, liquidity, amount_a_min, amount_b_min, account, deadline)
+                    .into_tokens(),
+            ),
+            (Change::Remove { liquidity }, None) => (
+                "removeLiquidityETH",
+                U256::zero(),
+                (token_a, liquidity, amount_a_min, amount_b_min, account, deadline).into_tokens(),
+            ),
+        };
+        let function = router02_contract.abi().function(function_name)?;
+        let data = function
+            .encode_input(&params)
+            .with_context(|| format!("Failed to encode {}", function_name))?;
+        let transact_obj =
+            router::transaction(&web3s, router02_addr, value, data, pending).await?;
+
+        let mut pair = pair;
+        let mut amounts = None;
+        let mut liquidity = match request.change {
+            Change::Add { .. } => None,
+            Change::Remove { liquidity } => Some(liquidity),
+        };
+
+        let submission = if dry_run {
+            if approval == Approval::Ready {
+                let output = chain::simulate(&web3s, account, &transact_obj).await?;
+                // Every variant returns the A side, then the B side, and adds the minted liquidity.
+                let returned: Vec<U256> = function
+                    .decode_output(&output.0)
+                    .with_context(|| format!("Invalid {} output", function_name))?
+                    .into_iter()
+                    .filter_map(Token::into_uint)
+                    .collect();
+                if let [amount_a, amount_b, rest @ ..] = returned.as_slice() {
+                    amounts = Some((*amount_a, *amount_b));
+                    liquidity = rest.first().copied().or(liquidity);
+                }
+            }
+
+            Submission::unsent(transact_obj)
+        } else {
+            chain::send(&web3s, transact_obj).await?
+        };
+        // Recorded before reading what it did, so a failed read can't lose a sent transaction.
+        let receipt = submission.receipt.clone();
+        outcome.outputs.extend(chain::submission_outputs(&submission));
+        outcome.submissions.push(submission);
+
+        if let Some(receipt) = &receipt {
+            // An add may have created the pair.
+            pair = router::pair(&factory, token_a, token_b).await?;
+            amounts = match request.change {
+                Change::Add { .. } => {
+                    liquidity = minted(receipt, pair, account);
+                    pair_amounts(receipt, pair, b"Mint(address,uint256,uint256)", token_a, token_b)
+                },
+                Change::Remove { .. } => pair_amounts(
+                    receipt,
+                    pair,
+                    b"Burn(address,uint256,uint256,address)",
+                    token_a,
+                    token_b,
+                ),
+            };
+            lp_balance = erc20::balance_of(&web3s, pair, account).await?;
+        } else if !dry_run {
+            // Sent but never confirmed, so there is nothing to read.
+            return Ok(());
+        }
+
+        outcome.outputs.insert("pair".to_string(), Value::String(format!("{:?}", pair)));
+
+        if let Some((amount_a, amount_b)) = amounts {
+            outcome.outputs.insert("amount_a".to_string(), chain::u256_to_value(amount_a));
+            outcome.outputs.insert("amount_b".to_string(), chain::u256_to_value(amount_b));
+        }
+        if let Some(liquidity) = liquidity {
+            outcome.outputs.insert("liquidity".to_string(), chain::u256_to_value(liquidity));
+        }
+        // A dry run only knows the balance the call would leave behind if it knows the liquidity.
+        let lp_balance = match (dry_run, request.change, liquidity) {
+            (false, ..) => Some(lp_balance),
+            (true, Change::Add { .. }, Some(liquidity)) => {
+                Some(lp_balance.saturating_add(liquidity))
+            },
+            (true, Change::Remove { .. }, Some(liquidity)) => {
+                Some(lp_balance.saturating_sub(liquidity))
+            },
+            (true, _, None) => None,
+        };
+        if let Some(lp_balance) = lp_balance {
+            outcome.outputs.insert("lp_balance".to_string(), chain::u256_to_value(lp_balance));
+        }
+        Ok(())
+    }
+    .await;
+
+    Ok(outcome.finish(result))
+}
+
+/// The token amounts of the pair's `Mint` or `Burn` event, ordered as A and B.
+fn pair_amounts(
+    receipt: &TransactionReceipt, pair: Address, event: &[u8], token_a: Address, token_b: Address,
+) -> Option<(U256, U256)> {
+    let topic = H256::from(keccak256(event));
+
+    receipt
+        .logs
+        .iter()
+        .find(|log| log.address == pair && log.topics.first() == Some(&topic))
+        .filter(|log| log.data.0.len() >= 64)
+        .map(|log| {
+            let amount0 = U256::from_big_endian(&log.data.0[0..32]);
+            let amount1 = U256::from_big_endian(&log.data.0[32..64]);
+            if token_a < token_b {
+                (amount0, amount1)
+            } else {
+                (amount1, amount0)
+            }
+        })
+}
+
+/// The LP tokens the pair minted to `recipient`.
+fn minted(receipt: &TransactionReceipt, pair: Address, recipient: Address) -> Option<U256> {
+    let topic = H256::from(keccak256(b"Transfer(address,address,uint256)"));
+    let from_topic = H256::from(Address::zero());
+    let to_topic = H256::from(recipient);
+
+    receipt
+        .logs
+        .iter()
+        .filter(|log| log.address == pair && log.topics.len() == 3 && log.topics[0] == topic)
+        .filter(|log| log.topics[1] == from_topic && log.topics[2] == to_topic)
+        .find(|log| log.data.0.len() == 32)
+        .map(|log| U256::from_big_endian(&log.data.0))
+}
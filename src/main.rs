@@ -8,15 +8,59 @@ use actix_web::web::{self};
 use actix_web::{middleware, App, HttpServer};
 use anyhow::Context;
 use std::env;
+use std::sync::Arc;
 
+pub mod aave;
+pub mod actions;
 pub mod api;
+pub mod chain;
+pub mod contract;
 pub mod dag;
+pub mod diff;
+pub mod erc20;
+pub mod execution;
+pub mod liquidity;
+pub mod multicall;
+pub mod nonce;
+pub mod price_feed;
 mod route;
+pub mod router;
+pub mod schema;
+pub mod sql_store;
+pub mod store;
+pub mod token;
+pub mod transfer;
+pub mod uniswap_v3;
+pub mod validation;
+pub mod weth;
+pub mod zap;
 
 pub fn initialize(cfg: &mut web::ServiceConfig) {
     route::setup_routes(cfg);
 }
 
+fn open_store() -> anyhow::Result<Arc<dyn store::ZapStore>> {
+    let store: Arc<dyn store::ZapStore> = match env::var("ZAP_STORE").as_deref().unwrap_or("file") {
+        "file" => {
+            let zaps_dir_path = env::var("ZAPS_DIR_PATH").context("ZAPS_DIR_PATH must be set")?;
+            Arc::new(store::FileStore::new(zaps_dir_path)?)
+        },
+        "sql" => {
+            let database_url = env::var("DATABASE_URL").context("DATABASE_URL must be set")?;
+            Arc::new(sql_store::SqlStore::new(&database_url)?)
+        },
+        other => anyhow::bail!("Unknown ZAP_STORE: {}", other),
+    };
+
+    if let Ok(data_file_path) = env::var("DATA_FILE_PATH") {
+        if store::import_legacy_graph(&*store, data_file_path.as_ref())? {
+            log::info!("Imported {} as the default zap", data_file_path);
+        }
+    }
+
+    Ok(store)
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().ok();
@@ -30,6 +74,13 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to initialize logging output");
 
     let listen_address = env::var("LISTEN_ADDRESS").expect("LISTEN_ADDRESS must be set");
+    // Opening a SQL store connects and migrates, and importing the legacy graph reads a file,
+    // both of which block.
+    let store = tokio::task::spawn_blocking(open_store)
+        .await
+        .expect("Opening the zap store panicked")
+        .expect("Failed to open the zap store");
+    let store = web::Data::from(store);
 
     log::info!("Starting up");
     HttpServer::new(move || {
@@ -40,7 +91,11 @@ async fn main() -> std::io::Result<()> {
             .allowed_methods(vec!["GET", "POST", "PUT", "OPTIONS", "DELETE", "PATCH", "HEAD"])
             .max_age(3600);
 
-        App::new().configure(initialize).wrap(cors).wrap(middleware::Logger::default())
+        App::new()
+            .app_data(store.clone())
+            .configure(initialize)
+            .wrap(cors)
+            .wrap(middleware::Logger::default())
     })
     .bind(listen_address)?
     .run()
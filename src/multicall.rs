@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::ethabi::{self, Token};
+use web3::transports::Http;
+use web3::types::{Address, U256};
+use web3::Web3;
+
+use crate::chain;
+
+/// Multicall3 is deployed at the same address on nearly every chain.
+const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+/// One read-only lookup. Whether it went through Multicall3 or not, its result comes back ABI
+/// encoded.
+#[derive(Debug, Clone)]
+pub enum Read {
+    /// `data` sent to `target` through `eth_call`.
+    Call { target: Address, data: Vec<u8> },
+    /// The native balance of an account, as a `uint256`.
+    Balance(Address),
+    /// The timestamp of the latest block, as a `uint256`.
+    BlockTimestamp,
+}
+
+/// The Multicall3 contract of the chain the provider is on. `MULTICALL_ADDRESSES` holds comma
+/// separated `<chain id>=<address>` entries that take precedence over the canonical address.
+async fn address(web3: &Web3<Http>) -> anyhow::Result<Address> {
+    let chain_id = web3.eth().chain_id().await.context("Failed to get chain id")?.as_u64();
+
+    match chain::configured_address("MULTICALL_ADDRESSES", chain_id)? {
+        Some(address) => Ok(address),
+        None => Ok(Address::from_str(MULTICALL3_ADDRESS)?),
+    }
+}
+
+fn abi() -> anyhow::Result<ethabi::Contract> {
+    ethabi::Contract::load(&include_bytes!("./multicall3_abi.json")[..])
+        .context("Invalid Multicall3 ABI")
+}
+
+/// Runs `reads` in one `aggregate3` call, or one by one when there is only one of them or
+/// Multicall3 is unavailable. Every read gets its own result, so one failing doesn't fail the
+/// others.
+pub async fn read_all(reads: &[Read]) -> anyhow::Result<Vec<anyhow::Result<Vec<u8>>>> {
+    let web3s = chain::connect()?;
+
+    if reads.len() > 1 {
+        match aggregate(&web3s, reads).await {
+            Ok(results) => return Ok(results),
+            Err(e) => {
+                log::warn!("Multicall failed, reading {} calls one by one: {:#}", reads.len(), e)
+            },
+        }
+    }
+
+    let mut results = Vec::with_capacity(reads.len());
+    for read in reads {
+        results.push(read_one(&web3s, read).await);
+    }
+
+    Ok(results)
+}
+
+async fn aggregate(
+    web3: &Web3<Http>, reads: &[Read],
+) -> anyhow::Result<Vec<anyhow::Result<Vec<u8>>>> {
+    let multicall = address(web3).await?;
+    let abi = abi()?;
+
+    let calls = reads
+        .iter()
+        .map(|read| {
+            let (target, data) = match read {
+                Read::Call { target, data } => (*target, data.clone()),
+                Read::Balance(account) => (
+                    multicall,
+                    abi.function("getEthBalance")?.encode_input(&[Token::Address(*account)])?,
+                ),
+                Read::BlockTimestamp => {
+                    (multicall, abi.function("getCurrentBlockTimestamp")?.encode_input(&[])?)
+                },
+            };
+            Ok(Token::Tuple(vec![Token::Address(target), Token::Bool(true), Token::Bytes(data)]))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let aggregate3 = abi.function("aggregate3")?;
+    let data = aggregate3.encode_input(&[Token::Array(calls)])?;
+    let returned = chain::call(web3, multicall, data).await?;
+    // A chain without Multicall3 answers with empty data, which doesn't decode.
+    let results = match aggregate3.decode_output(&returned.0)?.into_iter().next() {
+        Some(Token::Array(results)) if results.len() == reads.len() => results,
+        _ => anyhow::bail!("Multicall3 at {:?} returned malformed results", multicall),
+    };
+
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Token::Tuple(mut fields) if fields.len() == 2 => {
+                match (fields.remove(0), fields.remove(0)) {
+                    (Token::Bool(true), Token::Bytes(data)) => Ok(data),
+                    (Token::Bool(false), Token::Bytes(data)) => {
+                        Err(anyhow::anyhow!("Call reverted: {}", chain::decode_revert(&data)))
+                    },
+                    _ => Err(anyhow::anyhow!("Malformed Multicall3 result")),
+                }
+            },
+            _ => Err(anyhow::anyhow!("Malformed Multicall3 result")),
+        })
+        .collect())
+}
+
+async fn read_one(web3: &Web3<Http>, read: &Read) -> anyhow::Result<Vec<u8>> {
+    match read {
+        Read::Call { target, data } => chain::call(web3, *target, data.clone()).await.map(|b| b.0),
+        Read::Balance(account) => chain::balance(web3, *account).await.map(encode_uint),
+        Read::BlockTimestamp => chain::block_timestamp(web3).await.map(encode_uint),
+    }
+}
+
+fn encode_uint(value: U256) -> Vec<u8> { ethabi::encode(&[Token::Uint(value)]) }
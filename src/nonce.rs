@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use web3::{
+    transports::Http,
+    types::{Address, BlockNumber, H256, U256},
+    Web3,
+};
+
+lazy_static::lazy_static! {
+    /// Shared by every run so concurrent runs never hand out the same nonce twice.
+    pub static ref NONCES: NonceManager = NonceManager::default();
+}
+
+#[derive(Debug, Default)]
+struct AccountNonces {
+    /// The lowest nonce that has never been handed out.
+    next: U256,
+    /// Nonces below `next` that were handed out but never made it into the mempool.
+    gaps: BTreeSet<U256>,
+    /// Nonces handed out to transactions that haven't been broadcast yet.
+    reserved: BTreeSet<U256>,
+    /// Nonces of transactions that were broadcast but not mined yet.
+    pending: BTreeMap<U256, H256>,
+}
+
+/// Hands out nonces per signing account without asking the node for every transaction. Nonces of
+/// transactions that failed to broadcast or were dropped are reused before new ones, so the
+/// account never stalls on a gap.
+#[derive(Debug, Default)]
+pub struct NonceManager {
+    accounts: Mutex<HashMap<Address, AccountNonces>>,
+}
+
+/// A nonce handed out by `NonceManager::reserve`. Unless `sent` is called, dropping it releases
+/// the nonce, so a send that errors or is cancelled midway never leaves a gap the account stalls
+/// on.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    manager: &'a NonceManager,
+    account: Address,
+    pub nonce: U256,
+    sent: bool,
+}
+
+impl Reservation<'_> {
+    /// Marks the nonce as used by a broadcast transaction that has not been mined yet.
+    pub fn sent(mut self, hash: H256) {
+        self.sent = true;
+        if let Some(nonces) = self.manager.lock().get_mut(&self.account) {
+            nonces.reserved.remove(&self.nonce);
+            nonces.pending.insert(self.nonce, hash);
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.manager.release(self.account, self.nonce);
+        }
+    }
+}
+
+impl NonceManager {
+    // Never held across an await, so a std mutex is enough and `Reservation` can release on drop.
+    fn lock(&self) -> MutexGuard<'_, HashMap<Address, AccountNonces>> {
+        self.accounts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub async fn reserve(
+        &self, web3: &Web3<Http>, account: Address,
+    ) -> web3::Result<Reservation<'_>> {
+        // The node is asked without holding the lock, so a slow RPC doesn't hold up every other
+        // run. If another run got there first, its count wins.
+        if !self.lock().contains_key(&account) {
+            let next = pending_count(web3, account).await?;
+            self.lock().entry(account).or_insert(AccountNonces { next, ..Default::default() });
+        }
+
+        let mut accounts = self.lock();
+        let nonces = accounts.entry(account).or_default();
+        let nonce = match nonces.gaps.iter().next().copied() {
+            Some(gap) => {
+                nonces.gaps.remove(&gap);
+                gap
+            },
+            None => {
+                let nonce = nonces.next;
+                nonces.next += U256::one();
+                nonce
+            },
+        };
+        nonces.reserved.insert(nonce);
+        Ok(Reservation { manager: self, account, nonce, sent: false })
+    }
+
+    /// Forgets `nonce` once its transaction has been mined.
+    pub fn mined(&self, account: Address, nonce: U256) {
+        if let Some(nonces) = self.lock().get_mut(&account) {
+            nonces.pending.remove(&nonce);
+        }
+    }
+
+    /// Returns `nonce` for reuse because its transaction never reached, or fell out of, the
+    /// mempool.
+    pub fn release(&self, account: Address, nonce: U256) {
+        if let Some(nonces) = self.lock().get_mut(&account) {
+            nonces.reserved.remove(&nonce);
+            nonces.pending.remove(&nonce);
+            if nonce < nonces.next {
+                nonces.gaps.insert(nonce);
+            }
+        }
+    }
+
+    /// Re-reads the account's nonce from the `pending` block after the node rejected one of ours,
+    /// dropping local state the node no longer agrees with.
+    pub async fn resync(&self, web3: &Web3<Http>, account: Address) -> web3::Result<()> {
+        let chain_next = pending_count(web3, account).await?;
+        let mut accounts = self.lock();
+        let nonces = accounts.entry(account).or_default();
+
+        nonces.pending = nonces.pending.split_off(&chain_next);
+        nonces.gaps = nonces.gaps.split_off(&chain_next);
+        // Transactions we still consider pending, and those other runs are about to send, keep
+        // their nonces. Anything else above the node's view was never broadcast and can be
+        // handed out again.
+        let last_pending = nonces.pending.keys().next_back();
+        let last_reserved = nonces.reserved.iter().next_back();
+        let local_next = std::cmp::max(last_pending, last_reserved).map_or(chain_next, |n| *n + 1);
+        nonces.next = std::cmp::max(chain_next, local_next);
+        let mut gap = chain_next;
+        while gap < nonces.next {
+            if !nonces.pending.contains_key(&gap) && !nonces.reserved.contains(&gap) {
+                nonces.gaps.insert(gap);
+            }
+            gap += U256::one();
+        }
+
+        log::warn!("Resynced nonce of {:?} to {}", account, nonces.next);
+        Ok(())
+    }
+}
+
+async fn pending_count(web3: &Web3<Http>, account: Address) -> web3::Result<U256> {
+    web3.eth().transaction_count(account, Some(BlockNumber::Pending)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager that already knows `account`, so reserving never asks the node.
+    fn manager(account: Address, next: u64) -> NonceManager {
+        let manager = NonceManager::default();
+        manager.lock().insert(account, AccountNonces { next: next.into(), ..Default::default() });
+        manager
+    }
+
+    fn web3() -> Web3<Http> { Web3::new(Http::new("http://127.0.0.1:1").unwrap()) }
+
+    #[tokio::test]
+    async fn dropped_reservation_is_reused() {
+        let account = Address::repeat_byte(1);
+        let manager = manager(account, 7);
+
+        let reservation = manager.reserve(&web3(), account).await.unwrap();
+        assert_eq!(reservation.nonce, U256::from(7));
+        drop(reservation);
+        assert!(manager.lock()[&account].reserved.is_empty());
+
+        let reservation = manager.reserve(&web3(), account).await.unwrap();
+        assert_eq!(reservation.nonce, U256::from(7));
+    }
+
+    #[tokio::test]
+    async fn sent_reservation_stays_pending() {
+        let account = Address::repeat_byte(1);
+        let manager = manager(account, 7);
+
+        manager.reserve(&web3(), account).await.unwrap().sent(H256::repeat_byte(2));
+        let next = manager.reserve(&web3(), account).await.unwrap();
+        assert_eq!(next.nonce, U256::from(8));
+
+        let accounts = manager.lock();
+        assert_eq!(accounts[&account].pending.get(&7.into()), Some(&H256::repeat_byte(2)));
+        assert!(accounts[&account].gaps.is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use std::convert::TryFrom;
+use std::env;
+
+use anyhow::Context;
+use web3::ethabi::{self, ParamType, Token};
+use web3::types::{Address, U256};
+
+use crate::multicall::Read;
+
+/// The latest round of a Chainlink-style aggregator.
+#[derive(Debug)]
+pub struct Round {
+    pub round_id: U256,
+    /// The raw answer, scaled by `10^decimals`.
+    pub answer: U256,
+    pub decimals: u8,
+    /// The answer divided by `10^decimals`.
+    pub price: f64,
+    pub updated_at: U256,
+    /// Seconds between `updated_at` and the latest block.
+    pub age: u64,
+}
+
+/// How old, in seconds, a round may be before price feeds that don't set `max_age` reject it,
+/// from `PRICE_FEED_MAX_AGE`. Defaults to 3600.
+pub fn default_max_age() -> anyhow::Result<u64> {
+    match env::var("PRICE_FEED_MAX_AGE") {
+        Ok(max_age) => max_age.parse().context("Invalid PRICE_FEED_MAX_AGE"),
+        Err(_) => Ok(3600),
+    }
+}
+
+fn abi() -> anyhow::Result<ethabi::Contract> {
+    ethabi::Contract::load(&include_bytes!("./aggregator_abi.json")[..])
+        .context("Invalid aggregator ABI")
+}
+
+/// What `latest_round` needs read: `latestRoundData` and `decimals()` of `feed`, and the time of
+/// the latest block to age the round against. The age is measured in chain time rather than by
+/// the local clock, so a forked node judges staleness by its own time.
+pub fn reads(feed: Address) -> anyhow::Result<Vec<Read>> {
+    let abi = abi()?;
+    Ok(vec![
+        Read::Call { target: feed, data: abi.function("latestRoundData")?.encode_input(&[])? },
+        Read::Call { target: feed, data: abi.function("decimals")?.encode_input(&[])? },
+        Read::BlockTimestamp,
+    ])
+}
+
+/// Builds the latest round of `feed` from the results of its `reads`, normalizing the answer
+/// by `decimals()`.
+pub fn latest_round(
+    feed: Address, results: Vec<anyhow::Result<Vec<u8>>>,
+) -> anyhow::Result<Round> {
+    let abi = abi()?;
+    let mut results = results.into_iter();
+    let mut next = |name: &str| {
+        results.next().unwrap_or_else(|| Err(anyhow::anyhow!("{} was not read", name)))
+    };
+
+    let round_data = next("latestRoundData")
+        .and_then(|data| Ok(abi.function("latestRoundData")?.decode_output(&data)?))
+        .with_context(|| format!("Failed to read the latest round of {:?}", feed))?;
+    let decimals = next("decimals")
+        .and_then(|data| Ok(abi.function("decimals")?.decode_output(&data)?))
+        .with_context(|| format!("Failed to read the decimals of {:?}", feed))?;
+    let timestamp = next("block timestamp")
+        .and_then(|data| Ok(ethabi::decode(&[ParamType::Uint(256)], &data)?))
+        .context("Failed to read the latest block timestamp")?;
+
+    let uint = |tokens: &[Token], index: usize| match tokens.get(index) {
+        Some(Token::Uint(value)) | Some(Token::Int(value)) => *value,
+        _ => U256::zero(),
+    };
+    let (round_id, answer, updated_at) =
+        (uint(&round_data, 0), uint(&round_data, 1), uint(&round_data, 3));
+    let decimals = u8::try_from(uint(&decimals, 0).low_u64()).context("Too many decimals")?;
+
+    // The answer is an int256, so a set top bit is a negative price.
+    if answer.is_zero() || answer.bit(255) {
+        anyhow::bail!("Round {} of {:?} has no positive answer", round_id, feed);
+    } else if updated_at.is_zero() {
+        anyhow::bail!("Round {} of {:?} is not complete", round_id, feed);
+    }
+
+    let age = uint(&timestamp, 0).saturating_sub(updated_at);
+    let age = if age > U256::from(u64::MAX) { u64::MAX } else { age.as_u64() };
+
+    let price = answer.to_string().parse::<f64>()? / 10_f64.powi(decimals.into());
+
+    Ok(Round { round_id, answer, decimals, price, updated_at, age })
+}
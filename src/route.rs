@@ -7,6 +7,22 @@ pub fn setup_routes(cfg: &mut web::ServiceConfig) -> &mut web::ServiceConfig {
         .service((
             api::get_dag,
             api::update_dag,
+            api::validate_dag,
             api::play,
         ))
-}
\ No newline at end of file
+        .service((
+            api::create_zap,
+            api::list_zaps,
+            api::get_zap,
+            api::update_zap,
+            api::delete_zap,
+            api::play_zap,
+        ))
+        .service((
+            api::list_zap_versions,
+            api::get_zap_version,
+            api::diff_zap_versions,
+            api::rollback_zap,
+        ))
+        .service((api::list_runs, api::get_run, api::list_actions))
+}
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::contract::tokens::Tokenize;
+use web3::contract::{Contract, Options};
+use web3::ethabi::Token;
+use web3::signing::keccak256;
+use web3::transports::Http;
+use web3::types::{Address, Bytes, Log, TransactionParameters, TransactionReceipt, H256, U256};
+use web3::Web3;
+
+use crate::dag::ActionType;
+use crate::erc20::Approval;
+use crate::execution::{ActionOutcome, Submission};
+use crate::{chain, erc20};
+
+/// Gas limit of a router call that can't be estimated yet, see `transaction`.
+const UNESTIMATED_GAS_LIMIT: u64 = 500_000;
+
+/// How a swap is bounded: either the input is fixed and the output has a floor, or the output is
+/// fixed and the input has a ceiling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SwapAmounts {
+    ExactInput { amount_in: U256, amount_out_min: U256 },
+    ExactOutput { amount_out: U256, amount_in_max: U256 },
+}
+
+/// The Uniswap V2 router function a swap action calls.
+fn swap_function(action_type: ActionType) -> anyhow::Result<&'static str> {
+    Ok(match action_type {
+        ActionType::SwapExactETHForTokens => "swapExactETHForTokens",
+        ActionType::SwapExactTokensForTokens => "swapExactTokensForTokens",
+        ActionType::SwapExactTokensForETH => "swapExactTokensForETH",
+        ActionType::SwapTokensForExactTokens => "swapTokensForExactTokens",
+        ActionType::SwapETHForExactTokens => "swapETHForExactTokens",
+        ActionType::SwapTokensForExactETH => "swapTokensForExactETH",
+        ActionType::SwapExactTokensForTokensSupportingFeeOnTransferTokens => {
+            "swapExactTokensForTokensSupportingFeeOnTransferTokens"
+        },
+        ActionType::SwapExactETHForTokensSupportingFeeOnTransferTokens => {
+            "swapExactETHForTokensSupportingFeeOnTransferTokens"
+        },
+        ActionType::SwapExactTokensForETHSupportingFeeOnTransferTokens => {
+            "swapExactTokensForETHSupportingFeeOnTransferTokens"
+        },
+        _ => return Err(anyhow::anyhow!("{:?} is not a router swap", action_type)),
+    })
+}
+
+/// Whether the swap spends ETH sent along as `msg.value` rather than an ERC20 balance.
+fn pays_eth(action_type: ActionType) -> bool {
+    matches!(
+        action_type,
+        ActionType::SwapExactETHForTokens
+            | ActionType::SwapETHForExactTokens
+            | ActionType::SwapExactETHForTokensSupportingFeeOnTransferTokens
+    )
+}
+
+/// Whether the router unwraps the output and pays it out as ETH.
+fn receives_eth(action_type: ActionType) -> bool {
+    matches!(
+        action_type,
+        ActionType::SwapExactTokensForETH
+            | ActionType::SwapTokensForExactETH
+            | ActionType::SwapExactTokensForETHSupportingFeeOnTransferTokens
+    )
+}
+
+pub fn address() -> anyhow::Result<Address> {
+    let router02_address = env::var("ROUTER02_ADDRESS").context("ROUTER02_ADDRESS must be set")?;
+    Address::from_str(&router02_address).context("Invalid router02 address")
+}
+
+pub fn router(web3: &Web3<Http>) -> anyhow::Result<(Address, Contract<Http>)> {
+    let router02_addr = address()?;
+    let router02_contract =
+        Contract::from_json(web3.eth(), router02_addr, include_bytes!("./router02_abi.json"))?;
+
+    Ok((router02_addr, router02_contract))
+}
+
+/// Where a swap goes: along a fixed path of tokens, or along whichever route between `from` and
+/// `to` through the `ROUTE_INTERMEDIATES` quotes best.
+#[derive(Debug, Clone)]
+pub enum Route {
+    Path(Vec<Address>),
+    Auto { from: Address, to: Address },
+}
+
+/// The slippage applied to swaps that don't set `slippage_bps`, from `SLIPPAGE_BPS`. Defaults to
+/// 50, i.e. 0.5%.
+pub fn default_slippage_bps() -> anyhow::Result<u32> {
+    match env::var("SLIPPAGE_BPS") {
+        Ok(slippage_bps) => slippage_bps.parse().context("Invalid SLIPPAGE_BPS"),
+        Err(_) => Ok(50),
+    }
+}
+
+/// Asks the router what a swap of `amount` would cost right now: the output of an exact-input
+/// swap through `getAmountsOut`, or the input an exact-output swap needs through `getAmountsIn`.
+/// The quote ignores transfer fees, so fee-on-transfer swaps need a matching slippage. Returns the
+/// quoted path too, which for an automatic route is the best one found.
+pub async fn quote(
+    action_type: ActionType, route: &Route, amount: U256,
+) -> anyhow::Result<(Vec<Address>, U256)> {
+    let web3s = chain::connect()?;
+    let (_, router02_contract) = router(&web3s)?;
+
+    let (from, to) = match route {
+        Route::Path(path) => {
+            let quote = quote_path(&router02_contract, action_type, path, amount).await?;
+            return Ok((path.clone(), quote));
+        },
+        Route::Auto { from, to } => (*from, *to),
+    };
+
+    let factory = factory(&web3s, &router02_contract).await?;
+    let mut pairs = HashMap::new();
+    let mut best: Option<(Vec<Address>, U256)> = None;
+    for path in candidate_paths(from, to, &intermediates(&router02_contract).await?) {
+        if !has_liquidity(&web3s, &factory, &path, &mut pairs).await? {
+            continue;
+        }
+
+        let quote = match quote_path(&router02_contract, action_type, &path, amount).await {
+            Ok(quote) => quote,
+            Err(e) => {
+                log::debug!("Skipping route {:?}: {:#}", path, e);
+                continue;
+            },
+        };
+        let better = match &best {
+            None => true,
+            Some((_, best_quote)) if action_type.is_exact_output() => quote < *best_quote,
+            Some((_, best_quote)) => quote > *best_quote,
+        };
+        if better {
+            best = Some((path, quote));
+        }
+    }
+
+    best.with_context(|| format!("No route with liquidity from {:?} to {:?}", from, to))
+}
+
+async fn quote_path(
+    router02_contract: &Contract<Http>, action_type: ActionType, path: &[Address], amount: U256,
+) -> anyhow::Result<U256> {
+    let function_name =
+        if action_type.is_exact_output() { "getAmountsIn" } else { "getAmountsOut" };
+    let amounts: Vec<U256> = router02_contract
+        .query(function_name, (amount, path.to_vec()), None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to quote with {}", function_name))?;
+
+    let quote = if action_type.is_exact_output() { amounts.first() } else { amounts.last() };
+    quote.copied().with_context(|| format!("{} returned no amounts", function_name))
+}
+
+/// The tokens automatic routes may go through, from the comma separated `ROUTE_INTERMEDIATES`.
+/// Defaults to the router's WETH.
+async fn intermediates(router02_contract: &Contract<Http>) -> anyhow::Result<Vec<Address>> {
+    let intermediates = match env::var("ROUTE_INTERMEDIATES") {
+        Ok(intermediates) => intermediates,
+        Err(_) => {
+            return Ok(vec![weth(router02_contract).await?]);
+        },
+    };
+
+    intermediates
+        .split(',')
+        .map(str::trim)
+        .filter(|intermediate| !intermediate.is_empty())
+        .map(|intermediate| {
+            Address::from_str(intermediate)
+                .with_context(|| format!("Invalid route intermediate {}", intermediate))
+        })
+        .collect()
+}
+
+/// The direct path plus every path through one or two distinct intermediates.
+fn candidate_paths(from: Address, to: Address, intermediates: &[Address]) -> Vec<Vec<Address>> {
+    let hops: Vec<Address> =
+        intermediates.iter().copied().filter(|hop| *hop != from && *hop != to).collect();
+
+    let mut paths = vec![vec![from, to]];
+    for first in &hops {
+        paths.push(vec![from, *first, to]);
+    }
+    for first in &hops {
+        for second in hops.iter().filter(|second| *second != first) {
+            paths.push(vec![from, *first, *second, to]);
+        }
+    }
+
+    paths
+}
+
+/// Whether every hop of `path` has a pair with reserves on both sides. Pairs are looked up once
+/// and remembered in `pairs`, since candidate paths share most of their hops.
+async fn has_liquidity(
+    web3: &Web3<Http>, factory: &Contract<Http>, path: &[Address],
+    pairs: &mut HashMap<(Address, Address), bool>,
+) -> anyhow::Result<bool> {
+    for hop in path.windows(2) {
+        let key = if hop[0] < hop[1] { (hop[0], hop[1]) } else { (hop[1], hop[0]) };
+        let liquid = match pairs.get(&key) {
+            Some(liquid) => *liquid,
+            None => {
+                let liquid = pair_has_liquidity(web3, factory, key.0, key.1).await?;
+                pairs.insert(key, liquid);
+                liquid
+            },
+        };
+        if !liquid {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+async fn pair_has_liquidity(
+    web3: &Web3<Http>, factory: &Contract<Http>, token_a: Address, token_b: Address,
+) -> anyhow::Result<bool> {
+    let pair = pair(factory, token_a, token_b).await?;
+    if pair.is_zero() {
+        return Ok(false);
+    }
+
+    let (reserve0, reserve1) = reserves(web3, pair).await?;
+    Ok(!reserve0.is_zero() && !reserve1.is_zero())
+}
+
+/// The wrapped ETH the router trades in place of ETH.
+pub async fn weth(router02_contract: &Contract<Http>) -> anyhow::Result<Address> {
+    router02_contract
+        .query("WETH", (), None, Options::default(), None)
+        .await
+        .context("Failed to read the WETH address")
+}
+
+pub async fn factory(
+    web3: &Web3<Http>, router02_contract: &Contract<Http>,
+) -> anyhow::Result<Contract<Http>> {
+    let factory_address: Address = router02_contract
+        .query("factory", (), None, Options::default(), None)
+        .await
+        .context("Failed to read the factory address")?;
+
+    Ok(Contract::from_json(web3.eth(), factory_address, include_bytes!("./factory_abi.json"))?)
+}
+
+/// The pair of `token_a` and `token_b`, or the zero address if the pool doesn't exist.
+pub async fn pair(
+    factory: &Contract<Http>, token_a: Address, token_b: Address,
+) -> anyhow::Result<Address> {
+    factory
+        .query("getPair", (token_a, token_b), None, Options::default(), None)
+        .await
+        .context("Failed to look up pair")
+}
+
+/// The reserves of `pair` in the order of its tokens, i.e. sorted by address.
+pub async fn reserves(web3: &Web3<Http>, pair: Address) -> anyhow::Result<(U256, U256)> {
+    let pair_contract = Contract::from_json(web3.eth(), pair, include_bytes!("./pair_abi.json"))?;
+    let (reserve0, reserve1, _): (U256, U256, U256) = pair_contract
+        .query("getReserves", (), None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to read the reserves of {:?}", pair))?;
+
+    Ok((reserve0, reserve1))
+}
+
+/// Builds a call to the router. Until a pending approval is on chain the call can neither be
+/// estimated nor simulated, so it gets a fixed gas limit instead.
+pub async fn transaction(
+    web3: &Web3<Http>, router02_addr: Address, value: U256, data: Vec<u8>, approval_pending: bool,
+) -> anyhow::Result<TransactionParameters> {
+    if !approval_pending {
+        return chain::transaction(web3, router02_addr, value, data).await;
+    }
+
+    let gas_price = web3.eth().gas_price().await.context("Failed to get gas price")?;
+    Ok(TransactionParameters {
+        to: Some(router02_addr),
+        value,
+        gas_price: Some(gas_price),
+        gas: UNESTIMATED_GAS_LIMIT.into(),
+        data: Bytes(data),
+        ..Default::default()
+    })
+}
+
+/// Swaps along `path` through the router function of `action_type`. A
+/// token input is approved for the router first if `AUTO_APPROVE` allows it. Publishes
+/// `amount_in`, `amount_out`, `gas_used` and `status`, plus `tx_hash` once sent. Fee-on-transfer
+/// swaps return nothing from `eth_call`, so their dry runs leave the amounts out.
+pub async fn swap(
+    action_type: ActionType, path: Vec<Address>, amounts: SwapAmounts, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let account = chain::account()?;
+    let (router02_addr, router02_contract) = router(&web3s)?;
+    let function_name = swap_function(action_type)?;
+    let function = router02_contract.abi().function(function_name)?;
+
+    // Payable swaps take their input as `msg.value`, so the first amount argument is dropped.
+    let (value, mut params) = match (pays_eth(action_type), amounts) {
+        (true, SwapAmounts::ExactInput { amount_in, amount_out_min }) => {
+            (amount_in, vec![Token::Uint(amount_out_min)])
+        },
+        (true, SwapAmounts::ExactOutput { amount_out, amount_in_max }) => {
+            (amount_in_max, vec![Token::Uint(amount_out)])
+        },
+        (false, SwapAmounts::ExactInput { amount_in, amount_out_min }) => {
+            (U256::zero(), vec![Token::Uint(amount_in), Token::Uint(amount_out_min)])
+        },
+        (false, SwapAmounts::ExactOutput { amount_out, amount_in_max }) => {
+            (U256::zero(), vec![Token::Uint(amount_out), Token::Uint(amount_in_max)])
+        },
+    };
+
+    let spends = match amounts {
+        _ if pays_eth(action_type) => vec![],
+        SwapAmounts::ExactInput { amount_in, .. } => vec![(path[0], amount_in)],
+        SwapAmounts::ExactOutput { amount_in_max, .. } => vec![(path[0], amount_in_max)],
+    };
+    let mut outcome = ActionOutcome::default();
+    let result: anyhow::Result<()> = async {
+        let approval =
+            erc20::approve_spends(&web3s, router02_addr, &spends, dry_run, &mut outcome).await?;
+        if approval == Approval::Stopped {
+            return Ok(());
+        }
+
+        params.extend((path.clone(), account, deadline(&web3s).await?).into_tokens());
+        let data = function
+            .encode_input(&params)
+            .with_context(|| format!("Failed to encode {}", function_name))?;
+
+        let transact_obj =
+            transaction(&web3s, router02_addr, value, data, approval == Approval::Pending).await?;
+
+        if dry_run {
+            if approval == Approval::Ready {
+                let output = chain::simulate(&web3s, account, &transact_obj).await?;
+                let amounts: Vec<U256> = function
+                    .decode_output(&output.0)
+                    .context("Invalid swap output")?
+                    .into_iter()
+                    .next()
+                    .and_then(Token::into_array)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(Token::into_uint)
+                    .collect();
+
+                if let (Some(amount_in), Some(amount_out)) = (amounts.first(), amounts.last()) {
+                    let outputs = &mut outcome.outputs;
+                    outputs.insert("amount_in".to_string(), chain::u256_to_value(*amount_in));
+                    outputs.insert("amount_out".to_string(), chain::u256_to_value(*amount_out));
+                }
+            }
+
+            let submission = Submission::unsent(transact_obj);
+            outcome.outputs.extend(chain::submission_outputs(&submission));
+            outcome.submissions.push(submission);
+            return Ok(());
+        }
+
+        // The pairs are looked up before sending so that a failed read can't lose the submission.
+        let factory = factory(&web3s, &router02_contract).await?;
+        let first_pair = pair(&factory, path[0], path[1]).await?;
+        let last_pair = pair(&factory, path[path.len() - 2], path[path.len() - 1]).await?;
+
+        let submission = chain::send(&web3s, transact_obj).await?;
+        if let Some(receipt) = &submission.receipt {
+            // ETH outputs are paid to the router, which unwraps the WETH before forwarding it.
+            let recipient = if receives_eth(action_type) { router02_addr } else { account };
+            // Without the pair's Swap event the amounts are unknown rather than zero.
+            if let Some(amount_in) = swap_amount_in(receipt, first_pair) {
+                outcome.outputs.insert("amount_in".to_string(), chain::u256_to_value(amount_in));
+            }
+            if let Some(amount_out) = swap_amount_out(receipt, last_pair, recipient) {
+                outcome.outputs.insert("amount_out".to_string(), chain::u256_to_value(amount_out));
+            }
+        }
+        outcome.outputs.extend(chain::submission_outputs(&submission));
+        outcome.submissions.push(submission);
+        Ok(())
+    }
+    .await;
+
+    Ok(outcome.finish(result))
+}
+
+/// The Uniswap V2 `Swap` events `pair` emitted in a transaction.
+fn swap_logs(receipt: &TransactionReceipt, pair: Address) -> impl Iterator<Item = &Log> {
+    let swap_topic =
+        H256::from(keccak256(b"Swap(address,uint256,uint256,uint256,uint256,address)"));
+
+    receipt
+        .logs
+        .iter()
+        .filter(move |log| log.address == pair && log.topics.len() == 3)
+        .filter(move |log| log.topics[0] == swap_topic && log.data.0.len() == 128)
+}
+
+/// The input amount of the first hop, which is what its pair actually received.
+fn swap_amount_in(receipt: &TransactionReceipt, first_pair: Address) -> Option<U256> {
+    swap_logs(receipt, first_pair).next().and_then(|log| {
+        U256::from_big_endian(&log.data.0[0..32])
+            .checked_add(U256::from_big_endian(&log.data.0[32..64]))
+    })
+}
+
+/// The output amount the pair of the last hop paid out to `recipient`.
+fn swap_amount_out(
+    receipt: &TransactionReceipt, last_pair: Address, recipient: Address,
+) -> Option<U256> {
+    let recipient_topic = H256::from(recipient);
+
+    swap_logs(receipt, last_pair).filter(|log| log.topics[2] == recipient_topic).last().and_then(
+        |log| {
+            U256::from_big_endian(&log.data.0[64..96])
+                .checked_add(U256::from_big_endian(&log.data.0[96..128]))
+        },
+    )
+}
+
+/// How long a submitted transaction stays valid, in seconds.
+const DEADLINE_SECONDS: u64 = 300;
+
+/// The `deadline` router calls take: five minutes past the latest block, whose timestamp is
+/// what the contract checks it against.
+pub async fn deadline(web3: &Web3<Http>) -> anyhow::Result<U256> {
+    chain::block_timestamp(web3)
+        .await?
+        .checked_add(U256::from(DEADLINE_SECONDS))
+        .context("Deadline is out of range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(byte: u8) -> Address { Address::repeat_byte(byte) }
+
+    #[test]
+    fn candidate_paths_without_intermediates_is_the_direct_path() {
+        assert_eq!(candidate_paths(token(1), token(2), &[]), vec![vec![token(1), token(2)]]);
+    }
+
+    #[test]
+    fn candidate_paths_through_one_intermediate() {
+        assert_eq!(candidate_paths(token(1), token(2), &[token(9)]), vec![
+            vec![token(1), token(2)],
+            vec![token(1), token(9), token(2)],
+        ]);
+    }
+
+    #[test]
+    fn candidate_paths_through_two_intermediates() {
+        assert_eq!(candidate_paths(token(1), token(2), &[token(8), token(9)]), vec![
+            vec![token(1), token(2)],
+            vec![token(1), token(8), token(2)],
+            vec![token(1), token(9), token(2)],
+            vec![token(1), token(8), token(9), token(2)],
+            vec![token(1), token(9), token(8), token(2)],
+        ]);
+    }
+
+    #[test]
+    fn candidate_paths_skip_the_ends_as_intermediates() {
+        let paths = candidate_paths(token(1), token(2), &[token(1), token(9), token(2)]);
+        assert_eq!(paths, vec![vec![token(1), token(2)], vec![token(1), token(9), token(2)]]);
+    }
+}
@@ -0,0 +1,360 @@
+use std::convert::TryFrom;
+
+use anyhow::Context;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use uuid::Uuid;
+
+use crate::execution::RunReport;
+use crate::schema::{runs, zap_versions, zaps};
+use crate::store::ZapStore;
+use crate::zap::{Zap, ZapVersion};
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+enum Connections {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
+}
+
+/// Runs `$body` with `$conn` bound to a pooled connection of whichever backend is configured.
+/// The body is expanded once per backend, so it has to be valid diesel for both.
+macro_rules! with_connection {
+    ($connections:expr, |$conn:ident| $body:expr) => {
+        match $connections {
+            Connections::Postgres(pool) => {
+                let $conn = &mut pool.get()?;
+                $body
+            },
+            Connections::Sqlite(pool) => {
+                let $conn = &mut pool.get()?;
+                $body
+            },
+        }
+    };
+}
+
+/// Stores zaps in Postgres, or in SQLite when `DATABASE_URL` is a file path or `sqlite://` URL.
+pub struct SqlStore {
+    connections: Connections,
+}
+
+#[derive(Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = zaps, treat_none_as_null = true)]
+struct ZapRow {
+    id: String,
+    name: String,
+    description: Option<String>,
+    owner: Option<String>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    nodes: String,
+    version: i32,
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = zap_versions)]
+struct VersionRow {
+    zap_id: String,
+    version: i32,
+    author: Option<String>,
+    created_at: NaiveDateTime,
+    nodes: String,
+}
+
+#[derive(Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = runs, treat_none_as_null = true)]
+struct RunRow {
+    id: String,
+    zap_id: Option<String>,
+    trigger_source: String,
+    status: String,
+    started_at: NaiveDateTime,
+    finished_at: Option<NaiveDateTime>,
+    report: String,
+}
+
+impl ZapRow {
+    fn from_zap(zap: &Zap) -> anyhow::Result<Self> {
+        Ok(ZapRow {
+            id: zap.id.to_string(),
+            name: zap.name.clone(),
+            description: zap.description.clone(),
+            owner: zap.owner.clone(),
+            created_at: zap.created_at.naive_utc(),
+            updated_at: zap.updated_at.naive_utc(),
+            nodes: serde_json::to_string(&zap.nodes)?,
+            version: i32::try_from(zap.version)?,
+        })
+    }
+
+    fn into_zap(self) -> anyhow::Result<Zap> {
+        let id = Uuid::parse_str(&self.id).with_context(|| format!("Invalid zap id {}", self.id))?;
+        Ok(Zap {
+            id,
+            name: self.name,
+            description: self.description,
+            owner: self.owner,
+            version: u32::try_from(self.version)?,
+            created_at: Utc.from_utc_datetime(&self.created_at),
+            updated_at: Utc.from_utc_datetime(&self.updated_at),
+            nodes: serde_json::from_str(&self.nodes)
+                .with_context(|| format!("Invalid nodes in zap {}", id))?,
+        })
+    }
+}
+
+impl VersionRow {
+    fn from_version(version: &ZapVersion) -> anyhow::Result<Self> {
+        Ok(VersionRow {
+            zap_id: version.zap_id.to_string(),
+            version: i32::try_from(version.version)?,
+            author: version.author.clone(),
+            created_at: version.created_at.naive_utc(),
+            nodes: serde_json::to_string(&version.nodes)?,
+        })
+    }
+
+    fn into_version(self) -> anyhow::Result<ZapVersion> {
+        let zap_id = Uuid::parse_str(&self.zap_id)
+            .with_context(|| format!("Invalid zap id {}", self.zap_id))?;
+        let version = u32::try_from(self.version)?;
+        Ok(ZapVersion {
+            zap_id,
+            version,
+            author: self.author,
+            created_at: Utc.from_utc_datetime(&self.created_at),
+            nodes: serde_json::from_str(&self.nodes).with_context(|| {
+                format!("Invalid nodes in version {} of zap {}", version, zap_id)
+            })?,
+        })
+    }
+}
+
+impl RunRow {
+    fn from_run(run: &RunReport) -> anyhow::Result<Self> {
+        Ok(RunRow {
+            id: run.id.to_string(),
+            zap_id: run.zap_id.map(|zap_id| zap_id.to_string()),
+            trigger_source: run.trigger.clone(),
+            status: run.status.as_str().to_string(),
+            started_at: run.started_at.naive_utc(),
+            finished_at: run.finished_at.map(|finished_at| finished_at.naive_utc()),
+            report: serde_json::to_string(run)?,
+        })
+    }
+
+    fn into_run(self) -> anyhow::Result<RunReport> {
+        serde_json::from_str(&self.report).with_context(|| format!("Invalid run {}", self.id))
+    }
+}
+
+impl SqlStore {
+    /// Connects to `database_url` and brings the schema up to date.
+    pub fn new(database_url: &str) -> anyhow::Result<Self> {
+        let connections = if database_url.starts_with("postgres://")
+            || database_url.starts_with("postgresql://")
+        {
+            Connections::Postgres(Pool::builder().build(ConnectionManager::new(database_url))?)
+        } else {
+            let path = database_url.trim_start_matches("sqlite://");
+            // SQLite only allows one writer at a time, so don't let the pool hand out more.
+            Connections::Sqlite(Pool::builder().max_size(1).build(ConnectionManager::new(path))?)
+        };
+
+        with_connection!(&connections, |conn| conn
+            .run_pending_migrations(MIGRATIONS)
+            .map_err(|e| anyhow::anyhow!("Failed to run migrations: {}", e))?);
+
+        Ok(SqlStore { connections })
+    }
+}
+
+impl ZapStore for SqlStore {
+    fn list(&self) -> anyhow::Result<Vec<Zap>> {
+        let rows: Vec<ZapRow> = with_connection!(&self.connections, |conn| zaps::table
+            .order(zaps::created_at.asc())
+            .load(conn)?);
+
+        rows.into_iter().map(ZapRow::into_zap).collect()
+    }
+
+    fn get(&self, id: Uuid) -> anyhow::Result<Option<Zap>> {
+        let row: Option<ZapRow> = with_connection!(&self.connections, |conn| zaps::table
+            .find(id.to_string())
+            .first(conn)
+            .optional()?);
+
+        row.map(ZapRow::into_zap).transpose()
+    }
+
+    fn save(&self, zap: &Zap, version: &ZapVersion) -> anyhow::Result<()> {
+        let row = ZapRow::from_zap(zap)?;
+        let version_row = VersionRow::from_version(version)?;
+        with_connection!(&self.connections, |conn| conn.transaction(|conn| {
+            diesel::insert_into(zaps::table)
+                .values(&row)
+                .on_conflict(zaps::id)
+                .do_update()
+                .set(&row)
+                .execute(conn)?;
+            diesel::insert_into(zap_versions::table).values(&version_row).execute(conn)
+        })?);
+
+        Ok(())
+    }
+
+    fn list_versions(&self, id: Uuid) -> anyhow::Result<Vec<ZapVersion>> {
+        let rows: Vec<VersionRow> = with_connection!(&self.connections, |conn| zap_versions::table
+            .filter(zap_versions::zap_id.eq(id.to_string()))
+            .order(zap_versions::version.asc())
+            .load(conn)?);
+
+        rows.into_iter().map(VersionRow::into_version).collect()
+    }
+
+    fn get_version(&self, id: Uuid, version: u32) -> anyhow::Result<Option<ZapVersion>> {
+        let version = i32::try_from(version)?;
+        let row: Option<VersionRow> = with_connection!(&self.connections, |conn| {
+            zap_versions::table.find((id.to_string(), version)).first(conn).optional()?
+        });
+
+        row.map(VersionRow::into_version).transpose()
+    }
+
+    fn delete(&self, id: Uuid) -> anyhow::Result<bool> {
+        let id = id.to_string();
+        let deleted = with_connection!(&self.connections, |conn| conn.transaction(|conn| {
+            diesel::delete(zap_versions::table.filter(zap_versions::zap_id.eq(&id)))
+                .execute(conn)?;
+            diesel::delete(zaps::table.find(&id)).execute(conn)
+        })?);
+
+        Ok(deleted > 0)
+    }
+
+    fn save_run(&self, run: &RunReport) -> anyhow::Result<()> {
+        let row = RunRow::from_run(run)?;
+        with_connection!(&self.connections, |conn| diesel::insert_into(runs::table)
+            .values(&row)
+            .on_conflict(runs::id)
+            .do_update()
+            .set(&row)
+            .execute(conn)?);
+
+        Ok(())
+    }
+
+    fn list_runs(&self, zap_id: Option<Uuid>, limit: usize) -> anyhow::Result<Vec<RunReport>> {
+        let limit = i64::try_from(limit)?;
+        let rows: Vec<RunRow> = with_connection!(&self.connections, |conn| {
+            let mut query = runs::table.order(runs::started_at.desc()).limit(limit).into_boxed();
+            if let Some(zap_id) = zap_id {
+                query = query.filter(runs::zap_id.eq(zap_id.to_string()));
+            }
+            query.load(conn)?
+        });
+
+        rows.into_iter().map(RunRow::into_run).collect()
+    }
+
+    fn get_run(&self, id: Uuid) -> anyhow::Result<Option<RunReport>> {
+        let row: Option<RunRow> = with_connection!(&self.connections, |conn| runs::table
+            .find(id.to_string())
+            .first(conn)
+            .optional()?);
+
+        row.map(RunRow::into_run).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::zap::ZapInput;
+
+    fn store() -> SqlStore { SqlStore::new(":memory:").unwrap() }
+
+    fn input(root_id: u32) -> ZapInput {
+        ZapInput {
+            name: "zap".to_string(),
+            description: None,
+            owner: Some("alice".to_string()),
+            author: None,
+            nodes: serde_json::from_value(json!([{ "id": root_id, "zap_type": "ROOT" }])).unwrap(),
+        }
+    }
+
+    fn root_id(nodes: &[crate::dag::Node]) -> u32 { nodes[0].id }
+
+    #[test]
+    fn saves_zaps_with_their_versions() {
+        let store = store();
+        let (mut zap, version) = Zap::new(input(1));
+        store.save(&zap, &version).unwrap();
+        let version = zap.update(input(2));
+        store.save(&zap, &version).unwrap();
+
+        let saved = store.get(zap.id).unwrap().unwrap();
+        assert_eq!((saved.version, root_id(&saved.nodes)), (2, 2));
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        let versions = store.list_versions(zap.id).unwrap();
+        let numbers: Vec<_> = versions.iter().map(|version| version.version).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert_eq!(versions[0].author.as_deref(), Some("alice"));
+        assert_eq!(root_id(&store.get_version(zap.id, 1).unwrap().unwrap().nodes), 1);
+        assert!(store.get_version(zap.id, 3).unwrap().is_none());
+    }
+
+    #[test]
+    fn rollback_adds_a_version() {
+        let store = store();
+        let (mut zap, version) = Zap::new(input(1));
+        store.save(&zap, &version).unwrap();
+        let version = zap.update(input(2));
+        store.save(&zap, &version).unwrap();
+
+        let target = store.get_version(zap.id, 1).unwrap().unwrap();
+        let version = zap.rollback(&target, Some("bob".to_string()));
+        store.save(&zap, &version).unwrap();
+
+        let saved = store.get(zap.id).unwrap().unwrap();
+        assert_eq!((saved.version, root_id(&saved.nodes)), (3, 1));
+        let rolled_back = store.get_version(zap.id, 3).unwrap().unwrap();
+        assert_eq!(rolled_back.author.as_deref(), Some("bob"));
+        assert_eq!(root_id(&store.get_version(zap.id, 2).unwrap().unwrap().nodes), 2);
+    }
+
+    #[test]
+    fn never_overwrites_a_version() {
+        let store = store();
+        let (zap, version) = Zap::new(input(1));
+        store.save(&zap, &version).unwrap();
+
+        // A concurrent save that started from the same version claims the same number.
+        let mut stale = zap.clone();
+        stale.nodes = input(2).nodes;
+        assert!(store.save(&stale, &ZapVersion { nodes: stale.nodes.clone(), ..version }).is_err());
+
+        let saved = store.get(zap.id).unwrap().unwrap();
+        assert_eq!(root_id(&saved.nodes), 1);
+        assert_eq!(store.list_versions(zap.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn deletes_zaps_with_their_versions() {
+        let store = store();
+        let (zap, version) = Zap::new(input(1));
+        store.save(&zap, &version).unwrap();
+
+        assert!(store.delete(zap.id).unwrap());
+        assert!(store.get(zap.id).unwrap().is_none());
+        assert!(store.list_versions(zap.id).unwrap().is_empty());
+        assert!(!store.delete(zap.id).unwrap());
+    }
+}
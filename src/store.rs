@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use uuid::Uuid;
+
+use crate::execution::RunReport;
+use crate::zap::{Zap, ZapVersion, DEFAULT_ZAP_ID};
+
+/// Where zaps live. The handlers only ever talk to this trait, so the backend is picked once at
+/// startup from `ZAP_STORE`.
+pub trait ZapStore: Send + Sync {
+    /// Every zap, oldest first.
+    fn list(&self) -> anyhow::Result<Vec<Zap>>;
+
+    fn get(&self, id: Uuid) -> anyhow::Result<Option<Zap>>;
+
+    /// Inserts the zap, or replaces the stored one with the same id, and records `version`.
+    /// Fails if `version` already exists, since versions are never overwritten.
+    fn save(&self, zap: &Zap, version: &ZapVersion) -> anyhow::Result<()>;
+
+    /// Every version of the zap, oldest first.
+    fn list_versions(&self, id: Uuid) -> anyhow::Result<Vec<ZapVersion>>;
+
+    fn get_version(&self, id: Uuid, version: u32) -> anyhow::Result<Option<ZapVersion>>;
+
+    /// Returns `false` if there was nothing to delete.
+    fn delete(&self, id: Uuid) -> anyhow::Result<bool>;
+
+    /// Inserts the run, or replaces the stored one with the same id.
+    fn save_run(&self, run: &RunReport) -> anyhow::Result<()>;
+
+    /// The most recent `limit` runs, newest first, optionally only those of one zap.
+    fn list_runs(&self, zap_id: Option<Uuid>, limit: usize) -> anyhow::Result<Vec<RunReport>>;
+
+    fn get_run(&self, id: Uuid) -> anyhow::Result<Option<RunReport>>;
+}
+
+/// Saves the graph `DATA_FILE_PATH` used to hold as the default zap, unless the store already has
+/// one. Returns whether anything was imported.
+pub fn import_legacy_graph(store: &dyn ZapStore, path: &Path) -> anyhow::Result<bool> {
+    if store.get(DEFAULT_ZAP_ID)?.is_some() {
+        return Ok(false);
+    }
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+    };
+    let nodes = serde_json::from_slice(&data).with_context(|| format!("Invalid graph {:?}", path))?;
+
+    let (zap, version) = Zap::new_default(nodes);
+    store.save(&zap, &version)?;
+    Ok(true)
+}
+
+/// Keeps every zap as `<id>.json` inside a single directory, with its versions under
+/// `<id>.versions/<n>.json` and every run under `runs/<run id>.json`.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf { self.dir.join(format!("{}.json", id)) }
+
+    fn versions_dir(&self, id: Uuid) -> PathBuf { self.dir.join(format!("{}.versions", id)) }
+
+    fn version_path(&self, id: Uuid, version: u32) -> PathBuf {
+        self.versions_dir(id).join(format!("{}.json", version))
+    }
+
+    fn run_path(&self, id: Uuid) -> PathBuf { self.dir.join("runs").join(format!("{}.json", id)) }
+}
+
+impl ZapStore for FileStore {
+    fn list(&self) -> anyhow::Result<Vec<Zap>> {
+        let mut zaps = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                let data = fs::read(&path)?;
+                zaps.push(
+                    serde_json::from_slice(&data)
+                        .with_context(|| format!("Invalid zap file {:?}", path))?,
+                );
+            }
+        }
+
+        zaps.sort_by_key(|zap: &Zap| zap.created_at);
+        Ok(zaps)
+    }
+
+    fn get(&self, id: Uuid) -> anyhow::Result<Option<Zap>> {
+        match fs::read(self.path(id)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, zap: &Zap, version: &ZapVersion) -> anyhow::Result<()> {
+        let version_data = serde_json::to_vec(version)?;
+        let zap_data = serde_json::to_vec(zap)?;
+
+        // Creating the version file claims the version number, so it has to come first.
+        fs::create_dir_all(self.versions_dir(zap.id))?;
+        let version_path = self.version_path(zap.id, version.version);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&version_path)
+            .with_context(|| format!("Failed to create version {}", version.version))?;
+
+        // Write to a sibling file first so a crash never leaves a half-written zap behind.
+        let path = self.path(zap.id);
+        let tmp_path = path.with_extension("json.tmp");
+        let written = file
+            .write_all(&version_data)
+            .and_then(|()| fs::write(&tmp_path, zap_data))
+            .and_then(|()| fs::rename(&tmp_path, path));
+
+        // A version the zap never reached would block the next save from claiming its number.
+        if let Err(e) = written {
+            let _ = fs::remove_file(&version_path);
+            return Err(e).with_context(|| format!("Failed to save version {}", version.version));
+        }
+        Ok(())
+    }
+
+    fn list_versions(&self, id: Uuid) -> anyhow::Result<Vec<ZapVersion>> {
+        let entries = match fs::read_dir(self.versions_dir(id)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut versions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                let data = fs::read(&path)?;
+                versions.push(
+                    serde_json::from_slice(&data)
+                        .with_context(|| format!("Invalid version file {:?}", path))?,
+                );
+            }
+        }
+
+        versions.sort_by_key(|version: &ZapVersion| version.version);
+        Ok(versions)
+    }
+
+    fn get_version(&self, id: Uuid, version: u32) -> anyhow::Result<Option<ZapVersion>> {
+        match fs::read(self.version_path(id, version)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, id: Uuid) -> anyhow::Result<bool> {
+        match fs::remove_file(self.path(id)) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+
+        match fs::remove_dir_all(self.versions_dir(id)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_run(&self, run: &RunReport) -> anyhow::Result<()> {
+        let path = self.run_path(run.id);
+        fs::create_dir_all(self.dir.join("runs"))?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(run)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn list_runs(&self, zap_id: Option<Uuid>, limit: usize) -> anyhow::Result<Vec<RunReport>> {
+        let entries = match fs::read_dir(self.dir.join("runs")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut runs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                let data = fs::read(&path)?;
+                let run: RunReport = serde_json::from_slice(&data)
+                    .with_context(|| format!("Invalid run file {:?}", path))?;
+                if zap_id.map_or(true, |zap_id| run.zap_id == Some(zap_id)) {
+                    runs.push(run);
+                }
+            }
+        }
+
+        runs.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        runs.truncate(limit);
+        Ok(runs)
+    }
+
+    fn get_run(&self, id: Uuid) -> anyhow::Result<Option<RunReport>> {
+        match fs::read(self.run_path(id)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::*;
+use tokio::sync::Mutex;
+use web3::contract::{Contract, Options};
+use web3::transports::Http;
+use web3::types::{Address, H256, U256};
+use web3::Web3;
+
+use crate::{chain, erc20};
+
+/// Units any amount may be written in, whatever token it is in, with their decimals.
+const ETHER_UNITS: [(&str, u8); 4] = [("wei", 0), ("gwei", 9), ("ether", 18), ("eth", 18)];
+
+lazy_static::lazy_static! {
+    /// Token metadata never changes, so it is fetched once per chain and token.
+    static ref METADATA: Mutex<HashMap<(u64, Address), Metadata>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Metadata {
+    pub decimals: u8,
+    pub symbol: String,
+    pub name: String,
+}
+
+/// The `decimals()`, `symbol()` and `name()` of `token`, cached per chain.
+pub async fn metadata(web3: &Web3<Http>, token: Address) -> anyhow::Result<Metadata> {
+    let chain_id = web3.eth().chain_id().await.context("Failed to get chain id")?.as_u64();
+    if let Some(metadata) = METADATA.lock().await.get(&(chain_id, token)) {
+        return Ok(metadata.clone());
+    }
+
+    let contract = erc20::contract(web3, token)?;
+    let decimals: u8 = contract
+        .query("decimals", (), None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to read the decimals of {:?}", token))?;
+    let metadata = Metadata {
+        decimals,
+        symbol: text(&contract, "symbol").await,
+        name: text(&contract, "name").await,
+    };
+
+    METADATA.lock().await.insert((chain_id, token), metadata.clone());
+    Ok(metadata)
+}
+
+/// Reads `symbol()` or `name()`. Some older tokens return a `bytes32` instead of a `string`, and
+/// a token without either is still usable, so this never fails.
+async fn text(contract: &Contract<Http>, function: &str) -> String {
+    if let Ok(text) = contract.query(function, (), None, Options::default(), None).await {
+        return text;
+    }
+
+    match contract.query::<H256, _, _, _>(function, (), None, Options::default(), None).await {
+        Ok(bytes) => String::from_utf8_lossy(bytes.as_bytes()).trim_end_matches('\0').to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Whether `amount` is written with a unit, like `"1.5 ether"`, rather than in base units.
+pub fn has_unit(amount: &str) -> bool { amount.trim().contains(char::is_whitespace) }
+
+/// Converts an amount written with a unit into base units. `wei`, `gwei` and `ether` work for any
+/// amount; `token` and the symbol of `token` use its decimals and need the amount to be in a
+/// token. The conversion is exact, so an amount with more fractional digits than the unit has
+/// decimals is rejected rather than rounded.
+pub async fn to_base_units(amount: &str, token: Option<Address>) -> anyhow::Result<U256> {
+    let (number, unit) = amount
+        .trim()
+        .split_once(char::is_whitespace)
+        .with_context(|| format!("{} has no unit", amount))?;
+    let unit = unit.trim();
+
+    let ether_unit = ETHER_UNITS.iter().find(|(name, _)| name.eq_ignore_ascii_case(unit));
+    let decimals = match (ether_unit, token) {
+        (Some((_, decimals)), _) => *decimals,
+        (None, Some(token)) => {
+            let web3s = chain::connect()?;
+            let metadata = metadata(&web3s, token).await?;
+            if !unit.eq_ignore_ascii_case("token") && !unit.eq_ignore_ascii_case(&metadata.symbol)
+            {
+                anyhow::bail!("{} is in {}, but the token is {}", amount, unit, metadata.symbol);
+            }
+            metadata.decimals
+        },
+        (None, None) => {
+            anyhow::bail!("{} is not in a token, only wei, gwei and ether work", amount)
+        },
+    };
+
+    scale(number, decimals).with_context(|| format!("Invalid amount {}", amount))
+}
+
+/// `number` times `10^decimals`, exactly.
+fn scale(number: &str, decimals: u8) -> anyhow::Result<U256> {
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        anyhow::bail!("{} is not a decimal number", number);
+    }
+
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > decimals.into() {
+        anyhow::bail!("{} has more than {} decimals", number, decimals);
+    }
+
+    let digits = format!("{}{:0<width$}", whole, fraction, width = usize::from(decimals));
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(U256::zero());
+    }
+    U256::from_dec_str(digits).map_err(|_| anyhow::anyhow!("{} is too large", number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_whole_and_fractional_amounts() {
+        assert_eq!(scale("250", 6).unwrap(), U256::from(250_000_000));
+        assert_eq!(scale("1.5", 18).unwrap(), U256::from(1_500_000_000_000_000_000_u64));
+        assert_eq!(scale(".5", 1).unwrap(), U256::from(5));
+        assert_eq!(scale("3.", 2).unwrap(), U256::from(300));
+        assert_eq!(scale("0.0", 6).unwrap(), U256::zero());
+    }
+
+    #[test]
+    fn scale_ignores_trailing_zeros() {
+        assert_eq!(scale("1.2300000", 2).unwrap(), U256::from(123));
+        assert_eq!(scale("7.000", 0).unwrap(), U256::from(7));
+    }
+
+    #[test]
+    fn scale_rejects_too_many_decimals() {
+        assert!(scale("1.234", 2).is_err());
+        assert!(scale("0.5", 0).is_err());
+    }
+
+    #[test]
+    fn scale_rejects_what_isnt_a_number() {
+        for number in &[".", "", "1.2.3", "-1", "1e18", "0x10", " 1"] {
+            assert!(scale(number, 18).is_err(), "{:?} should not scale", number);
+        }
+    }
+
+    #[test]
+    fn scale_rejects_overflow() {
+        let max = U256::MAX.to_string();
+        assert_eq!(scale(&max, 0).unwrap(), U256::MAX);
+        assert!(scale(&max, 1).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::env;
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::contract::tokens::Tokenize;
+use web3::types::{Address, U256};
+
+use crate::execution::ActionOutcome;
+use crate::{chain, erc20};
+
+/// The recipients transfers may pay, from the comma separated `TRANSFER_ALLOWLIST`. Nothing may
+/// be transferred while it is unset.
+pub fn allowlist() -> anyhow::Result<Vec<Address>> {
+    let allowlist = match env::var("TRANSFER_ALLOWLIST") {
+        Ok(allowlist) => allowlist,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    allowlist
+        .split(',')
+        .map(str::trim)
+        .filter(|recipient| !recipient.is_empty())
+        .map(|recipient| {
+            Address::from_str(recipient)
+                .with_context(|| format!("Invalid TRANSFER_ALLOWLIST entry {}", recipient))
+        })
+        .collect()
+}
+
+/// Sends `amount` wei to `recipient`.
+pub async fn transfer_eth(
+    recipient: Address, amount: U256, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let parameters = chain::transaction(&web3s, recipient, amount, Vec::new()).await?;
+    let submission = chain::submit(&web3s, parameters, dry_run).await?;
+
+    let mut outcome = ActionOutcome::default();
+    outcome.outputs.extend(chain::submission_outputs(&submission));
+    outcome.outputs.insert("amount".to_string(), chain::u256_to_value(amount));
+    outcome.submissions.push(submission);
+
+    Ok(outcome)
+}
+
+/// Sends `amount` of `token` to `recipient` and publishes what the signing account has left.
+pub async fn transfer_erc20(
+    token: Address, recipient: Address, amount: U256, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let data = erc20::contract(&web3s, token)?
+        .abi()
+        .function("transfer")?
+        .encode_input(&(recipient, amount).into_tokens())
+        .context("Failed to encode transfer")?;
+    let parameters = chain::transaction(&web3s, token, U256::zero(), data).await?;
+    let submission = chain::submit(&web3s, parameters, dry_run).await?;
+
+    let mut balance = erc20::balance_of(&web3s, token, chain::account()?).await?;
+    if dry_run {
+        balance = balance.saturating_sub(amount);
+    }
+
+    let mut outcome = ActionOutcome::default();
+    outcome.outputs.extend(chain::submission_outputs(&submission));
+    outcome.outputs.insert("amount".to_string(), chain::u256_to_value(amount));
+    outcome.outputs.insert("balance".to_string(), chain::u256_to_value(balance));
+    outcome.submissions.push(submission);
+
+    Ok(outcome)
+}
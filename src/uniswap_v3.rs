@@ -0,0 +1,354 @@
+use std::env;
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::contract::{Contract, Options};
+use web3::ethabi::Token;
+use web3::signing::keccak256;
+use web3::transports::Http;
+use web3::types::{Address, TransactionReceipt, H256, U256};
+use web3::Web3;
+
+use crate::dag::ActionType;
+use crate::erc20::Approval;
+use crate::execution::{ActionOutcome, Submission};
+use crate::router::{self, SwapAmounts};
+use crate::{chain, erc20};
+
+/// Pool fees are in hundredths of a basis point, so a fee has to stay below 100%.
+pub const FEE_DENOMINATOR: u32 = 1_000_000;
+
+/// The pools a V3 swap goes through: `fees[i]` is the fee tier of the pool between `tokens[i]`
+/// and `tokens[i + 1]`.
+#[derive(Debug, Clone)]
+pub struct V3Path {
+    pub tokens: Vec<Address>,
+    pub fees: Vec<u32>,
+}
+
+impl V3Path {
+    /// The packed `token, fee, token, ...` encoding the router and quoter take for multi-hop
+    /// swaps, with 3 byte fees.
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(self.tokens.len() * 23);
+        for (i, token) in self.tokens.iter().enumerate() {
+            encoded.extend_from_slice(token.as_bytes());
+            if let Some(fee) = self.fees.get(i) {
+                encoded.extend_from_slice(&fee.to_be_bytes()[1..]);
+            }
+        }
+
+        encoded
+    }
+
+    fn token_in(&self) -> Address { self.tokens[0] }
+
+    fn token_out(&self) -> Address { self.tokens[self.tokens.len() - 1] }
+}
+
+fn address(name: &str) -> anyhow::Result<Address> {
+    let address = env::var(name).with_context(|| format!("{} must be set", name))?;
+    Address::from_str(&address).with_context(|| format!("Invalid {}", name))
+}
+
+/// Asks the Quoter at `V3_QUOTER_ADDRESS` what the swap would return or, for an exact-output
+/// swap, cost right now.
+pub async fn quote(action_type: ActionType, path: &V3Path, amount: U256) -> anyhow::Result<U256> {
+    let web3s = chain::connect()?;
+    let quoter = Contract::from_json(
+        web3s.eth(),
+        address("V3_QUOTER_ADDRESS")?,
+        include_bytes!("./quoter_abi.json"),
+    )?;
+
+    let no_price_limit = Token::Uint(U256::zero());
+    let (function_name, params) = match action_type {
+        ActionType::V3ExactInputSingle => (
+            "quoteExactInputSingle",
+            vec![
+                Token::Address(path.token_in()),
+                Token::Address(path.token_out()),
+                Token::Uint(path.fees[0].into()),
+                Token::Uint(amount),
+                no_price_limit,
+            ],
+        ),
+        ActionType::V3ExactOutputSingle => (
+            "quoteExactOutputSingle",
+            vec![
+                Token::Address(path.token_in()),
+                Token::Address(path.token_out()),
+                Token::Uint(path.fees[0].into()),
+                Token::Uint(amount),
+                no_price_limit,
+            ],
+        ),
+        ActionType::V3ExactInput => {
+            ("quoteExactInput", vec![Token::Bytes(path.encode()), Token::Uint(amount)])
+        },
+        _ => return Err(anyhow::anyhow!("{:?} is not a V3 swap", action_type)),
+    };
+
+    quoter
+        .query(function_name, params, None, Options::default(), None)
+        .await
+        .with_context(|| format!("Failed to quote with {}", function_name))
+}
+
+/// Swaps through the SwapRouter at `V3_ROUTER_ADDRESS`, approving the input token for it first
+/// if `AUTO_APPROVE` allows it. Inputs are always ERC20, so ETH has to be wrapped first.
+/// Publishes `amount_in` and `amount_out` next to the usual transaction outputs.
+pub async fn swap(
+    action_type: ActionType, path: &V3Path, amounts: SwapAmounts, dry_run: bool,
+) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let account = chain::account()?;
+    let router_addr = address("V3_ROUTER_ADDRESS")?;
+    let router_contract =
+        Contract::from_json(web3s.eth(), router_addr, include_bytes!("./swap_router_abi.json"))?;
+
+    let deadline = Token::Uint(router::deadline(&web3s).await?);
+    let no_price_limit = Token::Uint(U256::zero());
+    let (function_name, params, spend) = match (action_type, amounts) {
+        (ActionType::V3ExactInputSingle, SwapAmounts::ExactInput { amount_in, amount_out_min }) => (
+            "exactInputSingle",
+            vec![
+                Token::Address(path.token_in()),
+                Token::Address(path.token_out()),
+                Token::Uint(path.fees[0].into()),
+                Token::Address(account),
+                deadline,
+                Token::Uint(amount_in),
+                Token::Uint(amount_out_min),
+                no_price_limit,
+            ],
+            amount_in,
+        ),
+        (ActionType::V3ExactInput, SwapAmounts::ExactInput { amount_in, amount_out_min }) => (
+            "exactInput",
+            vec![
+                Token::Bytes(path.encode()),
+                Token::Address(account),
+                deadline,
+                Token::Uint(amount_in),
+                Token::Uint(amount_out_min),
+            ],
+            amount_in,
+        ),
+        (
+            ActionType::V3ExactOutputSingle,
+            SwapAmounts::ExactOutput { amount_out, amount_in_max },
+        ) => (
+            "exactOutputSingle",
+            vec![
+                Token::Address(path.token_in()),
+                Token::Address(path.token_out()),
+                Token::Uint(path.fees[0].into()),
+                Token::Address(account),
+                deadline,
+                Token::Uint(amount_out),
+                Token::Uint(amount_in_max),
+                no_price_limit,
+            ],
+            amount_in_max,
+        ),
+        _ => return Err(anyhow::anyhow!("{:?} can't swap {:?}", action_type, amounts)),
+    };
+
+    let mut outcome = ActionOutcome::default();
+    let result: anyhow::Result<()> = async {
+        let spends = [(path.token_in(), spend)];
+        let approval =
+            erc20::approve_spends(&web3s, router_addr, &spends, dry_run, &mut outcome).await?;
+        if approval == Approval::Stopped {
+            return Ok(());
+        }
+        let pending = approval == Approval::Pending;
+
+        let function = router_contract.abi().function(function_name)?;
+        let data = function
+            .encode_input(&[Token::Tuple(params)])
+            .with_context(|| format!("Failed to encode {}", function_name))?;
+        let transact_obj =
+            router::transaction(&web3s, router_addr, U256::zero(), data, pending).await?;
+
+        if dry_run {
+            if approval == Approval::Ready {
+                let output = chain::simulate(&web3s, account, &transact_obj).await?;
+                let returned = function
+                    .decode_output(&output.0)
+                    .with_context(|| format!("Invalid {} output", function_name))?
+                    .into_iter()
+                    .next()
+                    .and_then(Token::into_uint)
+                    .with_context(|| format!("{} returned nothing", function_name))?;
+
+                let (amount_in, amount_out) = match amounts {
+                    SwapAmounts::ExactInput { amount_in, .. } => (amount_in, returned),
+                    SwapAmounts::ExactOutput { amount_out, .. } => (returned, amount_out),
+                };
+                outcome.outputs.insert("amount_in".to_string(), chain::u256_to_value(amount_in));
+                outcome.outputs.insert("amount_out".to_string(), chain::u256_to_value(amount_out));
+            }
+
+            let submission = Submission::unsent(transact_obj);
+            outcome.outputs.extend(chain::submission_outputs(&submission));
+            outcome.submissions.push(submission);
+            return Ok(());
+        }
+
+        // The pools are looked up before sending so that a failed read can't lose the submission.
+        let (first_pool, last_pool) = end_pools(&web3s, &router_contract, path).await?;
+
+        let submission = chain::send(&web3s, transact_obj).await?;
+        let amounts = submission
+            .receipt
+            .as_ref()
+            .and_then(|receipt| pool_amounts(receipt, first_pool, last_pool));
+        if let Some((amount_in, amount_out)) = amounts {
+            outcome.outputs.insert("amount_in".to_string(), chain::u256_to_value(amount_in));
+            outcome.outputs.insert("amount_out".to_string(), chain::u256_to_value(amount_out));
+        }
+        outcome.outputs.extend(chain::submission_outputs(&submission));
+        outcome.submissions.push(submission);
+        Ok(())
+    }
+    .await;
+
+    Ok(outcome.finish(result))
+}
+
+/// The pools of the first and last hop of `path`, from the factory the router swaps through.
+async fn end_pools(
+    web3: &Web3<Http>, router_contract: &Contract<Http>, path: &V3Path,
+) -> anyhow::Result<(Address, Address)> {
+    let factory_address: Address = router_contract
+        .query("factory", (), None, Options::default(), None)
+        .await
+        .context("Failed to read the V3 factory address")?;
+    let factory =
+        Contract::from_json(web3.eth(), factory_address, include_bytes!("./v3_factory_abi.json"))?;
+
+    let hops = path.fees.len();
+    let first_pool = pool(&factory, path.tokens[0], path.tokens[1], path.fees[0]).await?;
+    let last_pool =
+        pool(&factory, path.tokens[hops - 1], path.tokens[hops], path.fees[hops - 1]).await?;
+    Ok((first_pool, last_pool))
+}
+
+/// The pool of `token_a` and `token_b` at the `fee` tier, or the zero address if there is none.
+async fn pool(
+    factory: &Contract<Http>, token_a: Address, token_b: Address, fee: u32,
+) -> anyhow::Result<Address> {
+    factory
+        .query("getPool", (token_a, token_b, U256::from(fee)), None, Options::default(), None)
+        .await
+        .context("Failed to look up V3 pool")
+}
+
+/// What went into `first_pool` and came out of `last_pool`, read from their `Swap` events. Pool
+/// deltas are signed: positive amounts were paid in, negative ones paid out.
+fn pool_amounts(
+    receipt: &TransactionReceipt, first_pool: Address, last_pool: Address,
+) -> Option<(U256, U256)> {
+    let swap_topic =
+        H256::from(keccak256(b"Swap(address,address,int256,int256,uint160,uint128,int24)"));
+    let deltas = |pool: Address| {
+        receipt
+            .logs
+            .iter()
+            .filter(move |log| log.address == pool && log.topics.len() == 3)
+            .filter(move |log| log.topics[0] == swap_topic && log.data.0.len() >= 64)
+            .map(|log| [signed(&log.data.0[0..32]), signed(&log.data.0[32..64])])
+    };
+
+    let amount_in = deltas(first_pool).next()?.iter().find(|(negative, _)| !negative)?.1;
+    let amount_out = deltas(last_pool).last()?.iter().find(|(negative, _)| *negative)?.1;
+    Some((amount_in, amount_out))
+}
+
+/// Splits a two's complement `int256` into its sign and magnitude.
+fn signed(word: &[u8]) -> (bool, U256) {
+    let value = U256::from_big_endian(word);
+    if word[0] & 0x80 == 0 {
+        return (false, value);
+    }
+
+    (true, (!value).overflowing_add(U256::one()).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use web3::types::{Bytes, Log};
+
+    use super::*;
+
+    #[test]
+    fn encode_packs_three_byte_fees_between_tokens() {
+        let path = V3Path {
+            tokens: vec![
+                Address::repeat_byte(0x11),
+                Address::repeat_byte(0x22),
+                Address::repeat_byte(0x33),
+            ],
+            fees: vec![500, 0x0a_0b0c],
+        };
+
+        let mut expected = vec![0x11; 20];
+        expected.extend_from_slice(&[0x00, 0x01, 0xf4]);
+        expected.extend_from_slice(&[0x22; 20]);
+        expected.extend_from_slice(&[0x0a, 0x0b, 0x0c]);
+        expected.extend_from_slice(&[0x33; 20]);
+        assert_eq!(path.encode(), expected);
+    }
+
+    #[test]
+    fn encode_single_pool() {
+        let path = V3Path {
+            tokens: vec![Address::repeat_byte(0xaa), Address::repeat_byte(0xbb)],
+            fees: vec![3000],
+        };
+
+        let encoded = path.encode();
+        assert_eq!(encoded.len(), 43);
+        assert_eq!(&encoded[20..23], &[0x00, 0x0b, 0xb8]);
+        assert_eq!(&encoded[23..], Address::repeat_byte(0xbb).as_bytes());
+    }
+
+    fn swap_log(pool: Address, amount0: i64, amount1: i64) -> Log {
+        let word = |amount: i64| {
+            let magnitude = U256::from(amount.unsigned_abs());
+            let value =
+                if amount < 0 { (!magnitude).overflowing_add(U256::one()).0 } else { magnitude };
+            let mut bytes = [0u8; 32];
+            value.to_big_endian(&mut bytes);
+            bytes.to_vec()
+        };
+        let topic = keccak256(b"Swap(address,address,int256,int256,uint160,uint128,int24)");
+
+        serde_json::from_value(serde_json::json!({
+            "address": pool,
+            "topics": [H256::from(topic), H256::zero(), H256::zero()],
+            "data": Bytes([word(amount0), word(amount1), vec![0; 96]].concat()),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pool_amounts_reads_the_path_pools_only() {
+        let (first, last, other) =
+            (Address::repeat_byte(1), Address::repeat_byte(2), Address::repeat_byte(9));
+        let receipt = TransactionReceipt {
+            logs: vec![
+                swap_log(other, 5, -5),
+                swap_log(first, 1000, -700),
+                swap_log(last, -650, 700),
+                swap_log(other, -1, 1),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(pool_amounts(&receipt, first, last), Some((1000.into(), 650.into())));
+        assert_eq!(pool_amounts(&receipt, first, Address::repeat_byte(3)), None);
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::{HashMap, HashSet};
+
+use daggy::petgraph::{algo::tarjan_scc, graph::DiGraph, visit::Bfs};
+use serde::*;
+
+use crate::actions;
+use crate::dag::{Node, NodeData, QueryType, ZapType};
+
+const ARITHMETIC_OPERATORS: [&str; 5] = ["+", "-", "*", "/", "%"];
+const CONDITION_OPERATORS: [&str; 6] = ["==", "!=", ">", ">=", "<", "<="];
+
+/// A field a node needs set, and how to read it off the node's data.
+pub type Field = (&'static str, fn(&NodeData) -> &Option<String>);
+
+#[derive(Debug, Serialize, PartialEq, Clone)]
+#[serde(tag = "kind")]
+pub enum Problem {
+    #[serde(rename = "DUPLICATE_NODE_ID")]
+    DuplicateNodeId { node_id: u32 },
+    #[serde(rename = "DANGLING_CHILD")]
+    DanglingChild { node_id: u32, child_id: u32 },
+    #[serde(rename = "CYCLE")]
+    Cycle { node_ids: Vec<u32> },
+    #[serde(rename = "MISSING_ROOT")]
+    MissingRoot,
+    #[serde(rename = "MULTIPLE_ROOTS")]
+    MultipleRoots { node_ids: Vec<u32> },
+    #[serde(rename = "UNREACHABLE_NODE")]
+    UnreachableNode { node_id: u32 },
+    #[serde(rename = "MISSING_FIELD")]
+    MissingField { node_id: u32, field: String },
+    #[serde(rename = "INVALID_FIELD")]
+    InvalidField { node_id: u32, field: String, message: String },
+    #[serde(rename = "UNKNOWN_OPERATOR")]
+    UnknownOperator { node_id: u32, operator: String },
+    #[serde(rename = "UNKNOWN_ACTION_TYPE")]
+    UnknownActionType { node_id: u32, action_type: String },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Report {
+    pub valid: bool,
+    pub problems: Vec<Problem>,
+}
+
+impl From<Vec<Problem>> for Report {
+    fn from(problems: Vec<Problem>) -> Self { Report { valid: problems.is_empty(), problems } }
+}
+
+/// Checks a zap graph for everything `dag::parse` and `dag::walk` rely on and returns every
+/// problem found, in node order. An empty list means the graph is safe to save and run.
+pub fn validate(nodes: &[Node]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let mut graph = DiGraph::<u32, ()>::new();
+    let mut indices = HashMap::new();
+    for node in nodes {
+        if indices.contains_key(&node.id) {
+            problems.push(Problem::DuplicateNodeId { node_id: node.id });
+            continue;
+        }
+        indices.insert(node.id, graph.add_node(node.id));
+    }
+
+    for node in nodes {
+        for edge in node.children.iter().flatten() {
+            match indices.get(&edge.id) {
+                Some(child_index) => {
+                    graph.update_edge(indices[&node.id], *child_index, ());
+                },
+                None => {
+                    problems.push(Problem::DanglingChild { node_id: node.id, child_id: edge.id })
+                },
+            }
+
+            if let Some(condition) = &edge.condition {
+                if !CONDITION_OPERATORS.contains(&condition.operator.as_str()) {
+                    problems.push(Problem::UnknownOperator {
+                        node_id: node.id,
+                        operator: condition.operator.clone(),
+                    });
+                }
+            }
+        }
+
+        check_node_data(node, &mut problems);
+    }
+
+    for component in tarjan_scc(&graph) {
+        let is_cycle = component.len() > 1
+            || component.iter().any(|index| graph.contains_edge(*index, *index));
+        if is_cycle {
+            let mut node_ids: Vec<u32> = component.iter().map(|index| graph[*index]).collect();
+            node_ids.sort_unstable();
+            problems.push(Problem::Cycle { node_ids });
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let roots: Vec<u32> = nodes
+        .iter()
+        .filter(|node| node.zap_type == ZapType::Root && seen.insert(node.id))
+        .map(|node| node.id)
+        .collect();
+    match roots.len() {
+        0 => problems.push(Problem::MissingRoot),
+        1 => {
+            let mut reachable = HashSet::new();
+            let mut bfs = Bfs::new(&graph, indices[&roots[0]]);
+            while let Some(index) = bfs.next(&graph) {
+                reachable.insert(graph[index]);
+            }
+
+            let mut seen = HashSet::new();
+            for node in nodes {
+                if seen.insert(node.id) && !reachable.contains(&node.id) {
+                    problems.push(Problem::UnreachableNode { node_id: node.id });
+                }
+            }
+        },
+        _ => problems.push(Problem::MultipleRoots { node_ids: roots }),
+    }
+
+    problems
+}
+
+fn check_node_data(node: &Node, problems: &mut Vec<Problem>) {
+    let mut missing = |field: &str| {
+        problems.push(Problem::MissingField { node_id: node.id, field: field.to_string() })
+    };
+
+    let data = match (&node.zap_type, &node.data) {
+        (ZapType::Root, _) => return,
+        (_, Some(data)) => data,
+        (_, None) => return missing("data"),
+    };
+
+    if node.zap_type == ZapType::Action {
+        match data.action_type.as_deref().filter(|action_type| !action_type.is_empty()) {
+            None => missing("action_type"),
+            Some(action_type) => match actions::handler(action_type) {
+                Some(handler) => {
+                    handler.validate(data).into_iter().for_each(missing);
+                    problems.extend(handler.invalid_fields(data).into_iter().map(
+                        |(field, message)| Problem::InvalidField {
+                            node_id: node.id,
+                            field: field.to_string(),
+                            message,
+                        },
+                    ));
+                },
+                None => problems.push(Problem::UnknownActionType {
+                    node_id: node.id,
+                    action_type: action_type.to_string(),
+                }),
+            },
+        }
+        return;
+    }
+
+    let required: &[Field] = match node.zap_type {
+        ZapType::Root | ZapType::Action => &[],
+        ZapType::Arithmetic => &[
+            ("left", |d| &d.left),
+            ("right", |d| &d.right),
+            ("operator", |d| &d.operator),
+            ("result", |d| &d.result),
+        ],
+        ZapType::Query => match data.query_type {
+            None => return missing("query_type"),
+            Some(QueryType::Call) => {
+                &[("contract_address", |d| &d.contract_address), ("function", |d| &d.function)]
+            },
+            Some(QueryType::Balance) => &[],
+            Some(QueryType::PriceFeed) => &[("feed_address", |d| &d.feed_address)],
+            Some(QueryType::AaveAccountData) => &[],
+        },
+    };
+
+    let mut unset = missing_fields(data, required);
+    if node.zap_type == ZapType::Query && data.query_type == Some(QueryType::Call) {
+        check_abi(data, &mut unset);
+    }
+    unset.into_iter().for_each(missing);
+
+    if node.zap_type == ZapType::Arithmetic {
+        if let Some(operator) = &data.operator {
+            if !operator.is_empty() && !ARITHMETIC_OPERATORS.contains(&operator.as_str()) {
+                problems.push(Problem::UnknownOperator {
+                    node_id: node.id,
+                    operator: operator.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// The fields of `required` that `data` leaves unset or empty.
+pub fn missing_fields(data: &NodeData, required: &[Field]) -> Vec<&'static str> {
+    required
+        .iter()
+        .filter(|(_, get)| get(data).as_deref().map_or(true, str::is_empty))
+        .map(|(field, _)| *field)
+        .collect()
+}
+
+/// A contract call needs an inline `abi` or the `abi_name` of a registered one.
+pub fn check_abi(data: &NodeData, missing: &mut Vec<&'static str>) {
+    if data.abi.is_none() && data.abi_name.as_deref().map_or(true, str::is_empty) {
+        missing.push("abi_name");
+    }
+}
+
+/// A swap needs either a `path` of at least two tokens or both ends of a direct swap.
+pub fn check_swap_path(data: &NodeData, missing: &mut Vec<&'static str>) {
+    match &data.path {
+        Some(path) if !path.is_empty() => {
+            if path.len() < 2 || path.iter().any(String::is_empty) {
+                missing.push("path");
+            }
+        },
+        _ => {
+            if data.token_from_address.as_deref().map_or(true, str::is_empty) {
+                missing.push("token_from_address");
+            }
+            if data.token_to_address.as_deref().map_or(true, str::is_empty) {
+                missing.push("token_to_address");
+            }
+        },
+    }
+}
+
+/// A multi-hop V3 swap needs a `path` with a fee tier per hop, or the ends and `fee` of a swap
+/// through a single pool.
+pub fn check_v3_path(data: &NodeData, missing: &mut Vec<&'static str>) {
+    match &data.path {
+        Some(path) if !path.is_empty() => {
+            if data.fees.as_ref().map_or(0, Vec::len) + 1 != path.len() {
+                missing.push("fees");
+            }
+        },
+        _ => {
+            if data.fee.as_deref().map_or(true, str::is_empty) {
+                missing.push("fee");
+            }
+        },
+    }
+    check_swap_path(data, missing);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A root followed by arithmetic nodes, which need nothing but their own data, with
+    /// `edges` as `(parent, child)` pairs.
+    fn graph(root: u32, node_ids: &[u32], edges: &[(u32, u32)]) -> Vec<Node> {
+        let node = |id: u32, zap_type: &str| {
+            let children: Vec<_> = edges
+                .iter()
+                .filter(|(parent, _)| *parent == id)
+                .map(|(_, child)| json!({ "id": child }))
+                .collect();
+            let data = json!({ "left": "1", "operator": "+", "right": "2", "result": "$x" });
+            json!({ "id": id, "zap_type": zap_type, "children": children, "data": data })
+        };
+
+        let mut nodes = vec![node(root, "ROOT")];
+        nodes.extend(node_ids.iter().map(|id| node(*id, "ARITHMETIC")));
+        serde_json::from_value(json!(nodes)).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_diamond() {
+        assert_eq!(validate(&graph(0, &[1, 2, 3], &[(0, 1), (0, 2), (1, 3), (2, 3)])), vec![]);
+    }
+
+    #[test]
+    fn finds_cycles() {
+        let problems = validate(&graph(0, &[1, 2, 3], &[(0, 1), (1, 2), (2, 3), (3, 1)]));
+        assert_eq!(problems, vec![Problem::Cycle { node_ids: vec![1, 2, 3] }]);
+
+        let problems = validate(&graph(0, &[1], &[(0, 1), (1, 1)]));
+        assert_eq!(problems, vec![Problem::Cycle { node_ids: vec![1] }]);
+    }
+
+    #[test]
+    fn finds_dangling_children() {
+        let problems = validate(&graph(0, &[1], &[(0, 1), (1, 7)]));
+        assert_eq!(problems, vec![Problem::DanglingChild { node_id: 1, child_id: 7 }]);
+    }
+
+    #[test]
+    fn finds_multiple_roots() {
+        let mut nodes = graph(0, &[1], &[(0, 1)]);
+        nodes.extend(graph(5, &[], &[]));
+        assert_eq!(validate(&nodes), vec![Problem::MultipleRoots { node_ids: vec![0, 5] }]);
+    }
+
+    #[test]
+    fn finds_a_missing_root() {
+        let nodes: Vec<Node> = graph(0, &[1], &[(0, 1)]).into_iter().skip(1).collect();
+        assert_eq!(validate(&nodes), vec![Problem::MissingRoot]);
+    }
+
+    #[test]
+    fn finds_unreachable_nodes() {
+        let problems = validate(&graph(0, &[1, 2, 3], &[(0, 1), (2, 3)]));
+        assert_eq!(problems, vec![
+            Problem::UnreachableNode { node_id: 2 },
+            Problem::UnreachableNode { node_id: 3 },
+        ]);
+    }
+
+    #[test]
+    fn finds_invalid_rate_modes() {
+        let mut nodes = graph(0, &[1], &[(0, 1)]);
+        let data = json!({
+            "action_type": "AAVE_REPAY",
+            "token_address": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            "amount": "max",
+            "interest_rate_mode": "fixed",
+        });
+        nodes[1] = serde_json::from_value(json!({ "id": 1, "zap_type": "ACTION", "data": data }))
+            .unwrap();
+
+        assert_eq!(validate(&nodes), vec![Problem::InvalidField {
+            node_id: 1,
+            field: "interest_rate_mode".to_string(),
+            message: "fixed is neither stable nor variable".to_string(),
+        }]);
+    }
+}
@@ -0,0 +1,74 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+use web3::contract::tokens::Tokenize;
+use web3::contract::Contract;
+use web3::transports::Http;
+use web3::types::{Address, U256};
+use web3::Web3;
+
+use crate::execution::ActionOutcome;
+use crate::{chain, erc20};
+
+/// The canonical WETH of the chains we run on. A local node is assumed to fork mainnet.
+const KNOWN_WETH_ADDRESSES: [(u64, &str); 4] = [
+    (1, "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"),
+    (5, "0xB4FBF271143F4FBf7B91A5ded31805e42b2208d6"),
+    (11155111, "0xfFf9976782d46CC05630D1f6eBAb18b2324d6B14"),
+    (31337, "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"),
+];
+
+/// The WETH contract of the chain the provider is on. `WETH_ADDRESSES` takes precedence over the
+/// known addresses.
+pub async fn address(web3: &Web3<Http>) -> anyhow::Result<Address> {
+    let chain_id = web3.eth().chain_id().await.context("Failed to get chain id")?.as_u64();
+
+    if let Some(address) = chain::configured_address("WETH_ADDRESSES", chain_id)? {
+        return Ok(address);
+    }
+
+    let (_, address) = KNOWN_WETH_ADDRESSES
+        .iter()
+        .find(|(id, _)| *id == chain_id)
+        .with_context(|| format!("No WETH address configured for chain {}", chain_id))?;
+    Ok(Address::from_str(address)?)
+}
+
+/// Wraps `amount` wei through `deposit()`. Publishes the resulting WETH `balance`.
+pub async fn wrap(amount: U256, dry_run: bool) -> anyhow::Result<ActionOutcome> {
+    convert(amount, true, dry_run).await
+}
+
+/// Unwraps `amount` WETH through `withdraw(uint)`. Publishes the resulting WETH `balance`.
+pub async fn unwrap(amount: U256, dry_run: bool) -> anyhow::Result<ActionOutcome> {
+    convert(amount, false, dry_run).await
+}
+
+async fn convert(amount: U256, wrap: bool, dry_run: bool) -> anyhow::Result<ActionOutcome> {
+    let web3s = chain::connect()?;
+    let weth = address(&web3s).await?;
+    let contract = Contract::from_json(web3s.eth(), weth, include_bytes!("./weth_abi.json"))?;
+
+    let (value, data) = if wrap {
+        (amount, contract.abi().function("deposit")?.encode_input(&[]))
+    } else {
+        (U256::zero(), contract.abi().function("withdraw")?.encode_input(&amount.into_tokens()))
+    };
+    let data = data.context("Failed to encode WETH call")?;
+    let parameters = chain::transaction(&web3s, weth, value, data).await?;
+    let submission = chain::submit(&web3s, parameters, dry_run).await?;
+
+    let mut balance = erc20::balance_of(&web3s, weth, chain::account()?).await?;
+    if dry_run {
+        balance =
+            if wrap { balance.saturating_add(amount) } else { balance.saturating_sub(amount) };
+    }
+
+    let mut outcome = ActionOutcome::default();
+    outcome.outputs.extend(chain::submission_outputs(&submission));
+    outcome.outputs.insert("amount".to_string(), chain::u256_to_value(amount));
+    outcome.outputs.insert("balance".to_string(), chain::u256_to_value(balance));
+    outcome.submissions.push(submission);
+
+    Ok(outcome)
+}
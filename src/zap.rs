@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use serde::*;
+use uuid::Uuid;
+
+use crate::dag;
+
+/// The zap behind the original single-graph endpoints `GET /`, `PUT /` and `POST /play`.
+pub const DEFAULT_ZAP_ID: Uuid = Uuid::nil();
+
+/// A named zap and the graph it runs.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Zap {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Number of the `ZapVersion` holding the current `nodes`.
+    #[serde(default)]
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub nodes: Vec<dag::Node>,
+}
+
+/// The fields a client may set when creating or replacing a zap.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ZapInput {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Recorded on the version this save creates; defaults to the owner.
+    #[serde(default)]
+    pub author: Option<String>,
+    pub nodes: Vec<dag::Node>,
+}
+
+/// An immutable snapshot of a zap's graph, created by every save.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ZapVersion {
+    pub zap_id: Uuid,
+    pub version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub nodes: Vec<dag::Node>,
+}
+
+impl Zap {
+    pub fn new(input: ZapInput) -> (Self, ZapVersion) { Self::with_id(Uuid::new_v4(), input) }
+
+    /// The default zap, holding `nodes` as its first version.
+    pub fn new_default(nodes: Vec<dag::Node>) -> (Self, ZapVersion) {
+        let input = ZapInput {
+            name: "default".to_string(),
+            description: None,
+            owner: None,
+            author: None,
+            nodes,
+        };
+        Self::with_id(DEFAULT_ZAP_ID, input)
+    }
+
+    pub fn with_id(id: Uuid, input: ZapInput) -> (Self, ZapVersion) {
+        let now = Utc::now();
+        let author = input.author.clone().or_else(|| input.owner.clone());
+        let zap = Zap {
+            id,
+            name: input.name,
+            description: input.description,
+            owner: input.owner,
+            version: 1,
+            created_at: now,
+            updated_at: now,
+            nodes: input.nodes,
+        };
+
+        let version = zap.snapshot(author);
+        (zap, version)
+    }
+
+    pub fn update(&mut self, input: ZapInput) -> ZapVersion {
+        let author = input.author.clone().or_else(|| input.owner.clone());
+        self.name = input.name;
+        self.description = input.description;
+        self.owner = input.owner;
+        self.set_nodes(input.nodes, author)
+    }
+
+    /// Replaces the graph as a new version, keeping the name, description and owner.
+    pub fn set_graph(&mut self, nodes: Vec<dag::Node>) -> ZapVersion {
+        self.set_nodes(nodes, self.owner.clone())
+    }
+
+    /// Restores the graph of an older version as a new version, so history is never rewritten.
+    pub fn rollback(&mut self, to: &ZapVersion, author: Option<String>) -> ZapVersion {
+        self.set_nodes(to.nodes.clone(), author.or_else(|| self.owner.clone()))
+    }
+
+    fn set_nodes(&mut self, nodes: Vec<dag::Node>, author: Option<String>) -> ZapVersion {
+        self.nodes = nodes;
+        self.version += 1;
+        self.updated_at = Utc::now();
+        self.snapshot(author)
+    }
+
+    fn snapshot(&self, author: Option<String>) -> ZapVersion {
+        ZapVersion {
+            zap_id: self.id,
+            version: self.version,
+            author,
+            created_at: self.updated_at,
+            nodes: self.nodes.clone(),
+        }
+    }
+}